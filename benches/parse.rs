@@ -0,0 +1,43 @@
+//! Times `IR::parse` on machine-generated sources of growing size. The
+//! per-byte cost should stay flat if parsing is linear.
+//!
+//! Run with `cargo bench --bench parse`.
+
+use std::{hint::black_box, time::Instant};
+
+use concussion::frontend::parser::{Program, IR};
+
+const MB: usize = 1 << 20;
+
+/// One huge loop around many small ones, the worst case for any matcher that
+/// rescans the program to find each partner.
+fn generate(size: usize) -> String {
+    const BODY: &str = "++[->+<]>.[-<<+>>]<\n";
+
+    let mut source = String::with_capacity(size + BODY.len());
+    source.push('[');
+    while source.len() < size - 1 {
+        source.push_str(BODY);
+    }
+    source.push(']');
+
+    source
+}
+
+fn main() {
+    for size in [MB, 2 * MB, 5 * MB, 10 * MB] {
+        let program = Program::from(generate(size).as_str());
+
+        let start = Instant::now();
+        let ir = IR::parse(&program).unwrap();
+        let elapsed = start.elapsed();
+
+        black_box(ir);
+        println!(
+            "{:>3} MB: {:>8.2?} ({:.2} ns/byte)",
+            size / MB,
+            elapsed,
+            elapsed.as_nanos() as f64 / size as f64
+        );
+    }
+}
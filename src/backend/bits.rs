@@ -0,0 +1,215 @@
+//! Compiles a [`BitIR`] to an ELF binary. The pointer is the index of the
+//! current bit, which `bt`, `btc` and friends address directly. Bits are
+//! written and read a byte at a time, least significant first, and a last
+//! partial byte of output is padded with zeros before the program exits.
+
+use iced_x86::code_asm::{self as asm, CodeAssembler, CodeLabel, IcedError};
+
+use super::{
+    compiler::CompilerError,
+    elf::{compile_to_elf, LabelMap, PhdrFlags, Segment, SegmentBuilder},
+};
+use crate::{
+    frontend::bits::{BitIR, BitInstruction, BIT_TAPE_LENGTH},
+    segment,
+};
+
+// bit pointer = RCX, bits waiting to be written = R12 and how many there are
+// = R13, bits of input left to read = R14 and how many there are = RBX
+
+/// Where everything a Boolfuck program needs in memory is.
+#[derive(Clone, Copy, Debug)]
+struct Memory {
+    tape: u64,
+    /// Room for the byte being read or written.
+    byte: u64,
+}
+
+fn emit_shift(a: &mut CodeAssembler, by: i64) -> Result<(), IcedError> {
+    let len = BIT_TAPE_LENGTH as i32;
+
+    if by > 0 {
+        a.add(asm::rcx, by as i32)?;
+        a.lea(asm::rax, asm::rcx - len)?;
+        a.cmp(asm::rcx, len)?;
+        a.cmovae(asm::rcx, asm::rax)?;
+    } else {
+        a.sub(asm::rcx, by.unsigned_abs() as i32)?;
+        a.lea(asm::rax, asm::rcx + len)?;
+        a.cmovs(asm::rcx, asm::rax)?;
+    }
+
+    Ok(())
+}
+
+fn emit_read(a: &mut CodeAssembler, memory: Memory) -> Result<(), IcedError> {
+    let mut have = a.create_label();
+    let mut one = a.create_label();
+    let mut done = a.create_label();
+
+    a.test(asm::ebx, asm::ebx)?;
+    a.jnz(have)?;
+    a.mov(asm::r15, asm::rcx)?;
+    a.xor(asm::eax, asm::eax)?;
+    a.xor(asm::edi, asm::edi)?;
+    a.mov(asm::rsi, memory.byte)?;
+    a.mov(asm::edx, 1)?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+    a.movzx(asm::r14d, asm::byte_ptr(memory.byte))?;
+    a.mov(asm::ebx, 8)?;
+    a.test(asm::rax, asm::rax)?;
+    a.jg(have)?;
+    // at the end of input a single 0 bit is read, and the next read tries
+    // again
+    a.xor(asm::r14d, asm::r14d)?;
+    a.mov(asm::ebx, 1)?;
+
+    a.set_label(&mut have)?;
+    a.dec(asm::ebx)?;
+    a.shr(asm::r14, 1)?;
+    a.jc(one)?;
+    a.btr(asm::qword_ptr(memory.tape), asm::rcx)?;
+    a.jmp(done)?;
+    a.set_label(&mut one)?;
+    a.bts(asm::qword_ptr(memory.tape), asm::rcx)?;
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+fn emit_write(
+    a: &mut CodeAssembler,
+    memory: Memory,
+    flush: CodeLabel,
+) -> Result<(), IcedError> {
+    let mut zero = a.create_label();
+    let mut done = a.create_label();
+
+    a.bt(asm::qword_ptr(memory.tape), asm::rcx)?;
+    a.jnc(zero)?;
+    a.bts(asm::r12, asm::r13)?;
+    a.set_label(&mut zero)?;
+    a.inc(asm::r13d)?;
+    a.cmp(asm::r13d, 8)?;
+    a.jne(done)?;
+    a.call(flush)?;
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+/// Emits a routine writing out the bits waiting to be written as a byte.
+fn emit_flush(
+    a: &mut CodeAssembler,
+    memory: Memory,
+    entry: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    a.set_label(entry)?;
+    a.mov(asm::byte_ptr(memory.byte), asm::r12b)?;
+    a.mov(asm::r15, asm::rcx)?;
+    a.mov(asm::eax, 1)?;
+    a.mov(asm::edi, 1)?;
+    a.mov(asm::rsi, memory.byte)?;
+    a.mov(asm::edx, 1)?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+    a.xor(asm::r12d, asm::r12d)?;
+    a.xor(asm::r13d, asm::r13d)?;
+    a.ret()?;
+
+    Ok(())
+}
+
+struct BitDataSegment;
+
+impl SegmentBuilder for BitDataSegment {
+    fn code(&self, _labels: &LabelMap) -> Result<Segment, CompilerError> {
+        let mut a = CodeAssembler::new(64)?;
+
+        let mut byte = a.create_label();
+        a.set_label(&mut byte)?;
+        a.db(&[0u8; 8])?;
+
+        let mut bit_tape = a.create_label();
+        a.set_label(&mut bit_tape)?;
+        a.db(&vec![0u8; BIT_TAPE_LENGTH as usize / 8])?;
+
+        Ok(segment!(a, byte, bit_tape))
+    }
+
+    fn flags(&self) -> PhdrFlags {
+        PhdrFlags::R | PhdrFlags::W
+    }
+}
+
+struct BitTextSegment<'a> {
+    ir: &'a BitIR,
+}
+
+impl SegmentBuilder for BitTextSegment<'_> {
+    fn code(&self, labels: &LabelMap) -> Result<Segment, CompilerError> {
+        use BitInstruction as B;
+
+        let mut a = CodeAssembler::new(64)?;
+        let memory = Memory {
+            tape: labels.get("bit_tape")?,
+            byte: labels.get("byte")?,
+        };
+        let instrs = &self.ir.instructions;
+
+        let mut _start = a.create_label();
+        a.set_label(&mut _start)?;
+        a.xor(asm::ecx, asm::ecx)?;
+        a.xor(asm::r12d, asm::r12d)?;
+        a.xor(asm::r13d, asm::r13d)?;
+        a.xor(asm::r14d, asm::r14d)?;
+        a.xor(asm::ebx, asm::ebx)?;
+
+        // every jump lands just past its partner
+        let mut jump_labels: Vec<_> =
+            instrs.iter().map(|_| a.create_label()).collect();
+        let mut flush = a.create_label();
+
+        for (pc, instr) in instrs.iter().enumerate() {
+            match instr.inner {
+                B::Shift(by) => emit_shift(&mut a, by)?,
+                B::Flip => a.btc(asm::qword_ptr(memory.tape), asm::rcx)?,
+                B::Read => emit_read(&mut a, memory)?,
+                B::Write => emit_write(&mut a, memory, flush)?,
+                B::JumpForward(target) => {
+                    a.bt(asm::qword_ptr(memory.tape), asm::rcx)?;
+                    a.jnc(jump_labels[target as usize])?;
+                    a.set_label(&mut jump_labels[pc])?;
+                }
+                B::JumpBackward(target) => {
+                    a.bt(asm::qword_ptr(memory.tape), asm::rcx)?;
+                    a.jc(jump_labels[target as usize])?;
+                    a.set_label(&mut jump_labels[pc])?;
+                }
+            }
+        }
+
+        // end, padding out the last byte
+        let mut exit = a.create_label();
+        a.test(asm::r13d, asm::r13d)?;
+        a.jz(exit)?;
+        a.call(flush)?;
+        a.set_label(&mut exit)?;
+        a.mov(asm::eax, 60)?;
+        a.xor(asm::edi, asm::edi)?;
+        a.syscall()?;
+
+        emit_flush(&mut a, memory, &mut flush)?;
+
+        Ok(segment!(a, _start))
+    }
+
+    fn flags(&self) -> PhdrFlags {
+        PhdrFlags::X | PhdrFlags::R
+    }
+}
+
+pub fn compile(ir: BitIR) -> Result<Vec<u8>, CompilerError> {
+    compile_to_elf(&[&BitDataSegment, &BitTextSegment { ir: &ir }])
+}
@@ -1,23 +1,30 @@
-use std::{collections::HashMap, u32};
+use std::collections::HashMap;
 
 use iced_x86::{
     code_asm::{
-        self,
-        asm_traits::{
-            CodeAsmAdd, CodeAsmInt, CodeAsmMov, CodeAsmSub, CodeAsmZero_bytes,
-        },
-        CodeAssembler, CodeLabel,
+        self, AsmMemoryOperand, AsmRegister16, AsmRegister32, AsmRegister64,
+        AsmRegister8, CodeAssembler, CodeLabel,
     },
     IcedError,
 };
 use thiserror::Error;
 
 use crate::{
-    frontend::parser::{Instruction, IR},
+    frontend::{
+        parser::{
+            CellWidth, Instruction, CALL_DEPTH_LIMIT, IR, PROCEDURE_COUNT,
+            SYSCALL_ARGUMENTS, TAPE_LENGTH,
+        },
+        span::Spanned,
+    },
+    interp::DUMP_RADIUS,
     segment,
 };
 
-use super::elf::{compile_to_elf, LabelMap, PhdrFlags, SegmentBuilder};
+use super::{
+    elf::{compile_to_elf, LabelMap, PhdrFlags, Segment, SegmentBuilder},
+    eval::{evaluate, State},
+};
 
 use code_asm as asm;
 
@@ -33,21 +40,121 @@ pub enum CompilerError {
     MissingLabel(&'static str),
 }
 
-const CELL_BUFFER_LENGTH: u32 = 30_000;
+const CELL_BUFFER_LENGTH: u32 = TAPE_LENGTH;
+
+/// Zeroed bytes either side of the tape, so 16 byte scans starting at any
+/// cell stay inside the segment.
+const CELL_BUFFER_PADDING: usize = 16;
+
+/// Room for the longest line a `#` dump can write, which is with 64 bit cells.
+const DUMP_BUFFER_LENGTH: usize = "ptr 0000:".len()
+    + (2 * DUMP_RADIUS + 1) * " 0123456789abcdef".len()
+    + "[]\n".len();
+
+/// Room for every procedure call that can be in progress, plus the calls a
+/// `#` dump makes inside the deepest one.
+const CALL_STACK_LENGTH: u64 = 8 * (CALL_DEPTH_LIMIT as u64 + 2);
+
+/// What `,` leaves in the current cell once stdin has been exhausted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofBehavior {
+    /// Leave the cell untouched.
+    #[default]
+    Unchanged,
+    /// Store 0 in the cell.
+    Zero,
+    /// Store 255 (-1) in the cell.
+    MaxValue,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompilerOptions {
+    pub eof: EofBehavior,
+    /// Run the program at compile time for up to this many steps, or until it
+    /// first reads input. If it finishes, the binary only writes out its
+    /// output, otherwise it writes out the output so far and carries on from
+    /// wherever it got to.
+    pub fuel: Option<u64>,
+}
 
 // dataptr = RCX/ECX
 
+/// Where the tape lives and how wide its cells are. The pointer always holds
+/// the byte address of the current cell.
+#[derive(Clone, Copy, Debug)]
+struct Tape {
+    base: u32,
+    width: CellWidth,
+}
+
+impl Tape {
+    /// Length of the tape in bytes.
+    fn len(self) -> u32 {
+        CELL_BUFFER_LENGTH * self.width.bytes()
+    }
+
+    fn end(self) -> u32 {
+        self.base + self.len()
+    }
+
+    /// Bytes between a cell and the one `cells` away, within a single lap of
+    /// the tape.
+    fn distance(self, cells: i64) -> i64 {
+        cells % CELL_BUFFER_LENGTH as i64 * self.width.bytes() as i64
+    }
+
+    /// Sizes a memory operand to a single cell.
+    fn cell(self, mem: AsmMemoryOperand) -> AsmMemoryOperand {
+        match self.width {
+            CellWidth::U8 => asm::byte_ptr(mem),
+            CellWidth::U16 => asm::word_ptr(mem),
+            CellWidth::U32 => asm::dword_ptr(mem),
+            CellWidth::U64 => asm::qword_ptr(mem),
+        }
+    }
+
+    /// `value` as a sign-extended 32-bit immediate, if it fits in one.
+    fn immediate(self, value: u64) -> Option<i32> {
+        let unused = 64 - self.width.bits();
+        let value = ((value << unused) as i64) >> unused;
+        value.try_into().ok()
+    }
+}
+
+/// A general purpose register at every width a cell can have.
+#[derive(Clone, Copy)]
+struct Gpr {
+    r8: AsmRegister8,
+    r16: AsmRegister16,
+    r32: AsmRegister32,
+    r64: AsmRegister64,
+}
+
+const RAX: Gpr = Gpr {
+    r8: asm::al,
+    r16: asm::ax,
+    r32: asm::eax,
+    r64: asm::rax,
+};
+
+const RDI: Gpr = Gpr {
+    r8: asm::dil,
+    r16: asm::di,
+    r32: asm::edi,
+    r64: asm::rdi,
+};
+
 fn emit_shift_left(
     a: &mut CodeAssembler,
-    base: u32,
+    tape: Tape,
     amount: u32, // precondition: amount <= |CELL_BUFFER_LENGTH|
 ) -> Result<(), IcedError> {
     let mut l = a.create_label();
 
-    a.lea(asm::rcx, asm::rcx - amount)?;
-    a.cmp(asm::ecx, base)?;
+    a.lea(asm::rcx, asm::rcx - tape.distance(amount as i64))?;
+    a.cmp(asm::ecx, tape.base)?;
     a.jae(l)?;
-    a.lea(asm::rcx, asm::rcx + CELL_BUFFER_LENGTH)?;
+    a.lea(asm::rcx, asm::rcx + tape.len())?;
 
     a.set_label(&mut l)?;
 
@@ -56,39 +163,309 @@ fn emit_shift_left(
 
 fn emit_shift_right(
     a: &mut CodeAssembler,
-    base: u32,
+    tape: Tape,
     amount: u32, // precondition: amount <= |CELL_BUFFER_LENGTH|
 ) -> Result<(), IcedError> {
     let mut l = a.create_label();
 
-    a.lea(asm::rcx, asm::rcx + amount)?;
-    a.cmp(asm::ecx, base + CELL_BUFFER_LENGTH)?;
+    a.lea(asm::rcx, asm::rcx + tape.distance(amount as i64))?;
+    a.cmp(asm::ecx, tape.end())?;
     a.jb(l)?;
-    a.sub(asm::ecx, CELL_BUFFER_LENGTH)?;
+    a.sub(asm::ecx, tape.len())?;
 
     a.set_label(&mut l)?;
 
     Ok(())
 }
 
-fn emit_add(a: &mut CodeAssembler, amount: u8) -> Result<(), IcedError> {
-    // iced should use imm8 (https://github.com/icedland/iced/issues/384)
-    a.add(asm::byte_ptr(asm::rcx), amount as u32)?;
+/// Points rdx at the cell `offset` away from the pointer, wrapping around the
+/// tape without branching. Clobbers rsi.
+fn emit_wrapped_address(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    offset: i64,
+) -> Result<(), IcedError> {
+    let distance = tape.distance(offset);
+
+    a.lea(asm::rdx, asm::rcx + distance)?;
+    if distance >= 0 {
+        a.lea(asm::rsi, asm::rdx - tape.len())?;
+        a.cmp(asm::edx, tape.end())?;
+        a.cmovae(asm::rdx, asm::rsi)?;
+    } else {
+        a.lea(asm::rsi, asm::rdx + tape.len())?;
+        a.cmp(asm::edx, tape.base)?;
+        a.cmovb(asm::rdx, asm::rsi)?;
+    }
+
+    Ok(())
+}
+
+fn emit_add(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    amount: u64,
+) -> Result<(), IcedError> {
+    match tape.immediate(amount) {
+        Some(amount) => a.add(tape.cell(cell), amount)?,
+        // only 64 bit cells get here
+        None => {
+            a.mov(asm::rax, amount)?;
+            a.add(asm::qword_ptr(cell), asm::rax)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn emit_sub(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    amount: u64,
+) -> Result<(), IcedError> {
+    match tape.immediate(amount) {
+        Some(amount) => a.sub(tape.cell(cell), amount)?,
+        None => {
+            a.mov(asm::rax, amount)?;
+            a.sub(asm::qword_ptr(cell), asm::rax)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn emit_set(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    value: u64,
+) -> Result<(), IcedError> {
+    match tape.immediate(value) {
+        Some(value) => a.mov(tape.cell(cell), value)?,
+        None => {
+            a.mov(asm::rax, value)?;
+            a.mov(asm::qword_ptr(cell), asm::rax)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds or subtracts the low cell-sized part of `reg` to the cell.
+fn emit_accumulate(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    reg: Gpr,
+    subtract: bool,
+) -> Result<(), IcedError> {
+    let cell = tape.cell(cell);
+
+    match (tape.width, subtract) {
+        (CellWidth::U8, false) => a.add(cell, reg.r8),
+        (CellWidth::U8, true) => a.sub(cell, reg.r8),
+        (CellWidth::U16, false) => a.add(cell, reg.r16),
+        (CellWidth::U16, true) => a.sub(cell, reg.r16),
+        (CellWidth::U32, false) => a.add(cell, reg.r32),
+        (CellWidth::U32, true) => a.sub(cell, reg.r32),
+        (CellWidth::U64, false) => a.add(cell, reg.r64),
+        (CellWidth::U64, true) => a.sub(cell, reg.r64),
+    }
+}
+
+/// Loads the current cell into rax, zero extending it.
+fn emit_load_cell(a: &mut CodeAssembler, tape: Tape) -> Result<(), IcedError> {
+    match tape.width {
+        CellWidth::U8 => a.movzx(asm::eax, asm::byte_ptr(asm::rcx)),
+        CellWidth::U16 => a.movzx(asm::eax, asm::word_ptr(asm::rcx)),
+        CellWidth::U32 => a.mov(asm::eax, asm::dword_ptr(asm::rcx)),
+        CellWidth::U64 => a.mov(asm::rax, asm::qword_ptr(asm::rcx)),
+    }
+}
+
+fn emit_mul_add_entry(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    skip: CodeLabel,
+) -> Result<(), IcedError> {
+    // the value stays in rax for every MulAdd that follows
+    emit_load_cell(a, tape)?;
+    a.test(asm::rax, asm::rax)?;
+    a.jz(skip)?;
+
+    Ok(())
+}
+
+fn emit_mul_add(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    offset: i64,
+    factor: u64,
+) -> Result<(), IcedError> {
+    emit_wrapped_address(a, tape, offset)?;
+
+    match factor {
+        1 => emit_accumulate(a, tape, asm::rdx + 0, RAX, false)?,
+        _ if factor == tape.width.max() => {
+            emit_accumulate(a, tape, asm::rdx + 0, RAX, true)?
+        }
+        _ => {
+            match (factor, tape.immediate(factor)) {
+                (2, _) => a.lea(asm::rdi, asm::rax + asm::rax)?,
+                (3, _) => a.lea(asm::rdi, asm::rax + asm::rax * 2)?,
+                (4, _) => a.lea(asm::rdi, asm::rax * 4)?,
+                (5, _) => a.lea(asm::rdi, asm::rax + asm::rax * 4)?,
+                (8, _) => a.lea(asm::rdi, asm::rax * 8)?,
+                (9, _) => a.lea(asm::rdi, asm::rax + asm::rax * 8)?,
+                (_, Some(factor)) => a.imul_3(asm::rdi, asm::rax, factor)?,
+                (_, None) => {
+                    a.mov(asm::rdi, factor)?;
+                    a.imul_2(asm::rdi, asm::rax)?;
+                }
+            }
+            emit_accumulate(a, tape, asm::rdx + 0, RDI, false)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Compares 16 bytes worth of cells in xmm1 against zero, leaving one bit
+/// per byte in eax.
+fn emit_zero_mask(a: &mut CodeAssembler, tape: Tape) -> Result<(), IcedError> {
+    match tape.width {
+        CellWidth::U8 => a.pcmpeqb(asm::xmm1, asm::xmm0)?,
+        CellWidth::U16 => a.pcmpeqw(asm::xmm1, asm::xmm0)?,
+        CellWidth::U32 => a.pcmpeqd(asm::xmm1, asm::xmm0)?,
+        CellWidth::U64 => unreachable!("SSE2 can't compare 64 bit cells"),
+    }
+    a.pmovmskb(asm::eax, asm::xmm1)?;
+
+    Ok(())
+}
+
+fn emit_scan_right_sse2(
+    a: &mut CodeAssembler,
+    tape: Tape,
+) -> Result<(), IcedError> {
+    let mut scan = a.create_label();
+    let mut found = a.create_label();
+    let mut done = a.create_label();
+
+    a.pxor(asm::xmm0, asm::xmm0)?;
+
+    a.set_label(&mut scan)?;
+    a.movdqu(asm::xmm1, asm::xmmword_ptr(asm::rcx))?;
+    emit_zero_mask(a, tape)?;
+    a.test(asm::eax, asm::eax)?;
+    a.jnz(found)?;
+    a.add(asm::rcx, 16)?;
+    a.cmp(asm::ecx, tape.end())?;
+    a.jb(scan)?;
+    // every cell up to the end was nonzero, carry on from the start
+    a.mov(asm::ecx, tape.base)?;
+    a.jmp(scan)?;
+
+    // wider cells set every bit of their mask, the lowest is their first byte
+    a.set_label(&mut found)?;
+    a.bsf(asm::eax, asm::eax)?;
+    a.add(asm::rcx, asm::rax)?;
+    a.cmp(asm::ecx, tape.end())?;
+    a.jb(done)?;
+    // the zero was in the padding past the end of the tape
+    a.mov(asm::ecx, tape.base)?;
+    a.jmp(scan)?;
+
+    a.set_label(&mut done)?;
 
     Ok(())
 }
 
-fn emit_sub(a: &mut CodeAssembler, amount: u8) -> Result<(), IcedError> {
-    a.sub(asm::byte_ptr(asm::rcx), amount as u32)?;
+fn emit_scan_left_sse2(
+    a: &mut CodeAssembler,
+    tape: Tape,
+) -> Result<(), IcedError> {
+    let mut scan = a.create_label();
+    let mut found = a.create_label();
+    let mut done = a.create_label();
+
+    let width = tape.width.bytes();
+
+    a.pxor(asm::xmm0, asm::xmm0)?;
+
+    // the current cell is the last one in the load
+    a.set_label(&mut scan)?;
+    a.movdqu(asm::xmm1, asm::xmmword_ptr(asm::rcx - (16 - width)))?;
+    emit_zero_mask(a, tape)?;
+    a.test(asm::eax, asm::eax)?;
+    a.jnz(found)?;
+    a.sub(asm::rcx, 16)?;
+    a.cmp(asm::ecx, tape.base)?;
+    a.jae(scan)?;
+    // every cell down to the start was nonzero, carry on from the end
+    a.mov(asm::ecx, tape.end() - width)?;
+    a.jmp(scan)?;
+
+    // the highest bit is the last byte of the cell, 15 bytes past the start
+    // of the load when it's the current cell
+    a.set_label(&mut found)?;
+    a.bsr(asm::eax, asm::eax)?;
+    a.lea(asm::rcx, asm::rcx + asm::rax - 15)?;
+    a.cmp(asm::ecx, tape.base)?;
+    a.jae(done)?;
+    // the zero was in the padding before the start of the tape
+    a.mov(asm::ecx, tape.end() - width)?;
+    a.jmp(scan)?;
+
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+fn emit_scan(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    stride: i64,
+) -> Result<(), IcedError> {
+    match (stride, tape.width) {
+        (_, CellWidth::U64) => (),
+        (1, _) => return emit_scan_right_sse2(a, tape),
+        (-1, _) => return emit_scan_left_sse2(a, tape),
+        _ => (),
+    }
+
+    let mut scan = a.create_label();
+    let mut done = a.create_label();
+
+    let amount = (stride.unsigned_abs() % CELL_BUFFER_LENGTH as u64) as u32;
+
+    a.cmp(tape.cell(asm::rcx + 0), 0)?;
+    a.je(done)?;
+    a.set_label(&mut scan)?;
+    if stride < 0 {
+        emit_shift_left(a, tape, amount)?;
+    } else {
+        emit_shift_right(a, tape, amount)?;
+    }
+    a.cmp(tape.cell(asm::rcx + 0), 0)?;
+    a.jne(scan)?;
+
+    a.set_label(&mut done)?;
 
     Ok(())
 }
 
-fn emit_write(a: &mut CodeAssembler) -> Result<(), IcedError> {
+/// Writes the low byte of the cell.
+fn emit_write(
+    a: &mut CodeAssembler,
+    cell: AsmMemoryOperand,
+) -> Result<(), IcedError> {
+    a.lea(asm::rsi, cell)?;
     a.mov(asm::r15, asm::rcx)?;
     a.mov(asm::rax, 1u64)?;
     a.mov(asm::rdi, 1u64)?;
-    a.mov(asm::rsi, asm::rcx)?;
     a.mov(asm::rdx, 1u64)?;
     a.syscall()?;
     a.mov(asm::rcx, asm::r15)?;
@@ -96,12 +473,268 @@ fn emit_write(a: &mut CodeAssembler) -> Result<(), IcedError> {
     Ok(())
 }
 
+/// Input baked into the data segment, which `,` reads before stdin.
+#[derive(Clone, Copy, Debug)]
+struct EmbeddedInput {
+    /// Address of the first byte.
+    base: u64,
+    len: usize,
+    /// Address of a qword counting the bytes read so far.
+    cursor: u64,
+}
+
+/// Where `,` gets its bytes from.
+#[derive(Clone, Copy, Debug)]
+struct Input {
+    eof: EofBehavior,
+    embedded: Option<EmbeddedInput>,
+}
+
+/// Reads the next embedded byte into the cell rsi points at and jumps to
+/// `done`, or falls through once every byte has been read.
+fn emit_read_embedded(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    input: EmbeddedInput,
+    done: CodeLabel,
+) -> Result<(), IcedError> {
+    let mut stdin = a.create_label();
+
+    a.mov(asm::rax, asm::qword_ptr(input.cursor))?;
+    a.cmp(asm::rax, input.len as i32)?;
+    a.jae(stdin)?;
+    a.movzx(asm::edi, asm::byte_ptr(asm::rax + input.base as i64))?;
+    a.inc(asm::rax)?;
+    a.mov(asm::qword_ptr(input.cursor), asm::rax)?;
+    match tape.width {
+        CellWidth::U8 => a.mov(asm::byte_ptr(asm::rsi), RDI.r8)?,
+        CellWidth::U16 => a.mov(asm::word_ptr(asm::rsi), RDI.r16)?,
+        CellWidth::U32 => a.mov(asm::dword_ptr(asm::rsi), RDI.r32)?,
+        CellWidth::U64 => a.mov(asm::qword_ptr(asm::rsi), RDI.r64)?,
+    }
+    a.jmp(done)?;
+
+    a.set_label(&mut stdin)?;
+
+    Ok(())
+}
+
+/// Reads a byte into the low byte of the cell, zero extending it to the
+/// rest of the cell.
+fn emit_read(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    input: Input,
+) -> Result<(), IcedError> {
+    let mut done = a.create_label();
+
+    a.lea(asm::rsi, cell)?;
+    if let Some(embedded) = input.embedded {
+        emit_read_embedded(a, tape, embedded, done)?;
+    }
+
+    a.mov(asm::r15, asm::rcx)?;
+    a.mov(asm::rax, 0u64)?;
+    a.mov(asm::rdi, 0u64)?;
+    a.mov(asm::rdx, 1u64)?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+
+    // read returns 0 at EOF, or a negative errno which we treat the same way
+    let value = match input.eof {
+        EofBehavior::Unchanged => None,
+        EofBehavior::Zero => Some(0),
+        EofBehavior::MaxValue => Some(-1),
+    };
+    let extend = tape.width != CellWidth::U8;
+
+    if value.is_some() || extend {
+        let mut at_eof = a.create_label();
+
+        // the syscall leaves rsi pointing at the cell
+        a.test(asm::rax, asm::rax)?;
+        if extend {
+            a.jle(if value.is_some() { at_eof } else { done })?;
+            a.movzx(asm::eax, asm::byte_ptr(asm::rsi))?;
+            match tape.width {
+                CellWidth::U8 => unreachable!(),
+                CellWidth::U16 => a.mov(asm::word_ptr(asm::rsi), asm::ax)?,
+                CellWidth::U32 => a.mov(asm::dword_ptr(asm::rsi), asm::eax)?,
+                CellWidth::U64 => a.mov(asm::qword_ptr(asm::rsi), asm::rax)?,
+            }
+            if value.is_some() {
+                a.jmp(done)?;
+            }
+        } else {
+            a.jg(done)?;
+        }
+        if let Some(value) = value {
+            a.set_label(&mut at_eof)?;
+            a.mov(tape.cell(asm::rsi + 0), value)?;
+        }
+    } else if input.embedded.is_none() {
+        return Ok(());
+    }
+
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+/// Writes `len` bytes at `address` to stdout, before the pointer is set up.
+fn emit_output(
+    a: &mut CodeAssembler,
+    address: u64,
+    len: usize,
+) -> Result<(), IcedError> {
+    a.mov(asm::rax, 1u64)?;
+    a.mov(asm::rdi, 1u64)?;
+    a.mov(asm::rsi, address)?;
+    a.mov(asm::rdx, len as u64)?;
+    a.syscall()?;
+
+    Ok(())
+}
+
+/// Waits for every forked process to exit, so they can finish writing their
+/// output.
+fn emit_wait_for_forks(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    let mut wait = a.create_label();
+
+    // wait4(-1, NULL, 0, NULL) until there are no children left
+    a.zero_bytes()?;
+    a.set_label(&mut wait)?;
+    a.mov(asm::rax, 61u64)?;
+    a.mov(asm::rdi, -1i64)?;
+    a.xor(asm::esi, asm::esi)?;
+    a.xor(asm::edx, asm::edx)?;
+    a.xor(asm::r10d, asm::r10d)?;
+    a.syscall()?;
+    a.test(asm::rax, asm::rax)?;
+    a.jns(wait)?;
+
+    Ok(())
+}
+
+fn emit_exit(a: &mut CodeAssembler) -> Result<(), IcedError> {
+    a.mov(asm::rax, 60u64)?;
+    a.mov(asm::rdi, 0u64)?;
+    a.syscall()?;
+
+    Ok(())
+}
+
+/// Emits a routine to call for `#`, which writes the same line as
+/// [`crate::interp::dump`] to stderr. It builds the line up in `buffer` and
+/// leaves the pointer in rcx as it was.
+fn emit_debug_dump(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    buffer: u64,
+    entry: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    let mut cell = a.create_label();
+    let mut open = a.create_label();
+    let mut close = a.create_label();
+    let mut hex = a.create_label();
+    let mut digit = a.create_label();
+    let mut letter = a.create_label();
+
+    let width = tape.width.bytes();
+    let radius = DUMP_RADIUS as i32;
+
+    a.set_label(entry)?;
+    a.mov(asm::r15, asm::rcx)?;
+    a.mov(asm::rdi, buffer)?;
+    a.mov(asm::dword_ptr(asm::rdi), i32::from_le_bytes(*b"ptr "))?;
+    a.add(asm::rdi, 4)?;
+
+    // the index of the current cell
+    a.mov(asm::rax, asm::rcx)?;
+    a.sub(asm::rax, tape.base as i32)?;
+    a.shr(asm::rax, width.trailing_zeros())?;
+    a.mov(asm::edx, 4)?;
+    a.call(hex)?;
+    a.mov(asm::byte_ptr(asm::rdi), b':' as i32)?;
+    a.inc(asm::rdi)?;
+
+    // r8 walks the window from its leftmost cell, r9 counts the cells
+    a.lea(asm::r8, asm::rcx + tape.distance(-(DUMP_RADIUS as i64)))?;
+    a.lea(asm::r9, asm::r8 + tape.len())?;
+    a.cmp(asm::r8d, tape.base)?;
+    a.cmovb(asm::r8, asm::r9)?;
+    a.xor(asm::r9d, asm::r9d)?;
+
+    a.set_label(&mut cell)?;
+    a.mov(asm::byte_ptr(asm::rdi), b' ' as i32)?;
+    a.inc(asm::rdi)?;
+    a.cmp(asm::r9d, radius)?;
+    a.jne(open)?;
+    a.mov(asm::byte_ptr(asm::rdi), b'[' as i32)?;
+    a.inc(asm::rdi)?;
+    a.set_label(&mut open)?;
+    match tape.width {
+        CellWidth::U8 => a.movzx(asm::eax, asm::byte_ptr(asm::r8))?,
+        CellWidth::U16 => a.movzx(asm::eax, asm::word_ptr(asm::r8))?,
+        CellWidth::U32 => a.mov(asm::eax, asm::dword_ptr(asm::r8))?,
+        CellWidth::U64 => a.mov(asm::rax, asm::qword_ptr(asm::r8))?,
+    }
+    a.mov(asm::edx, 2 * width)?;
+    a.call(hex)?;
+    a.cmp(asm::r9d, radius)?;
+    a.jne(close)?;
+    a.mov(asm::byte_ptr(asm::rdi), b']' as i32)?;
+    a.inc(asm::rdi)?;
+    a.set_label(&mut close)?;
+    a.add(asm::r8, width as i32)?;
+    a.lea(asm::r10, asm::r8 - tape.len())?;
+    a.cmp(asm::r8d, tape.end())?;
+    a.cmovae(asm::r8, asm::r10)?;
+    a.inc(asm::r9d)?;
+    a.cmp(asm::r9d, 2 * radius + 1)?;
+    a.jb(cell)?;
+
+    a.mov(asm::byte_ptr(asm::rdi), b'\n' as i32)?;
+    a.inc(asm::rdi)?;
+    a.mov(asm::rsi, buffer)?;
+    a.mov(asm::rdx, asm::rdi)?;
+    a.sub(asm::rdx, asm::rsi)?;
+    a.mov(asm::rax, 1u64)?;
+    a.mov(asm::rdi, 2u64)?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+    a.ret()?;
+
+    // writes the low edx nibbles of rax in hex at rdi, moving rdi past them
+    a.set_label(&mut hex)?;
+    a.add(asm::rdi, asm::rdx)?;
+    a.mov(asm::r10, asm::rdi)?;
+    a.set_label(&mut digit)?;
+    a.mov(asm::r11d, asm::eax)?;
+    a.and(asm::r11d, 0xf)?;
+    a.add(asm::r11d, b'0' as i32)?;
+    a.cmp(asm::r11d, b'9' as i32)?;
+    a.jbe(letter)?;
+    a.add(asm::r11d, (b'a' - b'9' - 1) as i32)?;
+    a.set_label(&mut letter)?;
+    a.dec(asm::r10)?;
+    a.mov(asm::byte_ptr(asm::r10), asm::r11b)?;
+    a.shr(asm::rax, 4)?;
+    a.dec(asm::edx)?;
+    a.jnz(digit)?;
+    a.ret()?;
+
+    Ok(())
+}
+
 fn emit_jump_forward(
     a: &mut CodeAssembler,
+    tape: Tape,
     target: CodeLabel,
     position: &mut CodeLabel,
 ) -> Result<(), IcedError> {
-    a.cmp(asm::byte_ptr(asm::rcx), 0)?;
+    a.cmp(tape.cell(asm::rcx + 0), 0)?;
     a.je(target)?;
 
     a.set_label(position)?;
@@ -111,10 +744,11 @@ fn emit_jump_forward(
 
 fn emit_jump_backward(
     a: &mut CodeAssembler,
+    tape: Tape,
     target: CodeLabel,
     position: &mut CodeLabel,
 ) -> Result<(), IcedError> {
-    a.cmp(asm::byte_ptr(asm::rcx), 0)?;
+    a.cmp(tape.cell(asm::rcx + 0), 0)?;
     a.jne(target)?;
 
     a.set_label(position)?;
@@ -122,20 +756,528 @@ fn emit_jump_backward(
     Ok(())
 }
 
-struct DataSegment;
+/// What can go wrong while the compiled code runs. Each one writes its
+/// message to stderr and exits with status 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RuntimeError {
+    /// Defining a procedure numbered past the end of the table.
+    ProcedureNumber,
+    UndefinedProcedure,
+    CallDepth,
+    Fork,
+    SyscallArguments,
+    SyscallArgumentKind,
+    /// A syscall argument passing the address of cells that wrap around the
+    /// end of the tape.
+    SyscallBuffer,
+}
+
+impl RuntimeError {
+    const ALL: [RuntimeError; 7] = [
+        RuntimeError::ProcedureNumber,
+        RuntimeError::UndefinedProcedure,
+        RuntimeError::CallDepth,
+        RuntimeError::Fork,
+        RuntimeError::SyscallArguments,
+        RuntimeError::SyscallArgumentKind,
+        RuntimeError::SyscallBuffer,
+    ];
+
+    /// Every error `ir` could run into.
+    fn possible(ir: &IR) -> Vec<RuntimeError> {
+        use Instruction as I;
+
+        Self::ALL
+            .into_iter()
+            .filter(|error| {
+                ir.instructions.iter().any(|i| match error {
+                    RuntimeError::ProcedureNumber => {
+                        matches!(i.inner, I::DefineProc(_))
+                    }
+                    RuntimeError::UndefinedProcedure
+                    | RuntimeError::CallDepth => i.inner == I::CallProc,
+                    RuntimeError::Fork => i.inner == I::Fork,
+                    RuntimeError::SyscallArguments
+                    | RuntimeError::SyscallArgumentKind
+                    | RuntimeError::SyscallBuffer => i.inner == I::Syscall,
+                })
+            })
+            .collect()
+    }
+
+    /// The label of its message in the data segment.
+    fn label(self) -> &'static str {
+        match self {
+            RuntimeError::ProcedureNumber => "procedure_number_error",
+            RuntimeError::UndefinedProcedure => "undefined_procedure_error",
+            RuntimeError::CallDepth => "call_depth_error",
+            RuntimeError::Fork => "fork_error",
+            RuntimeError::SyscallArguments => "syscall_arguments_error",
+            RuntimeError::SyscallArgumentKind => "syscall_argument_kind_error",
+            RuntimeError::SyscallBuffer => "syscall_buffer_error",
+        }
+    }
+
+    fn message(self) -> String {
+        match self {
+            RuntimeError::ProcedureNumber => format!(
+                "can't define a procedure numbered {PROCEDURE_COUNT} or \
+                 above\n"
+            ),
+            RuntimeError::UndefinedProcedure => {
+                "a procedure is called before it's defined\n".to_owned()
+            }
+            RuntimeError::CallDepth => format!(
+                "more than {CALL_DEPTH_LIMIT} procedure calls are in \
+                 progress\n"
+            ),
+            RuntimeError::Fork => "could not fork\n".to_owned(),
+            RuntimeError::SyscallArguments => format!(
+                "a syscall takes at most {SYSCALL_ARGUMENTS} arguments\n"
+            ),
+            RuntimeError::SyscallArgumentKind => {
+                "unknown kind of syscall argument\n".to_owned()
+            }
+            RuntimeError::SyscallBuffer => {
+                "syscall argument runs off the end of the tape\n".to_owned()
+            }
+        }
+    }
+}
+
+/// The routine reporting each error the program could run into.
+struct ErrorRoutines(Vec<(RuntimeError, CodeLabel)>);
+
+impl ErrorRoutines {
+    fn get(&self, error: RuntimeError) -> CodeLabel {
+        self.0
+            .iter()
+            .find(|(e, _)| *e == error)
+            .map(|&(_, label)| label)
+            .expect("only possible errors are reported")
+    }
+}
+
+/// Where the compiled code keeps track of procedures.
+#[derive(Clone, Copy, Debug)]
+struct Procedures {
+    /// Address of a table of where each procedure starts, which is zero for
+    /// the ones that haven't been defined.
+    table: u64,
+    /// Lowest address of the call stack.
+    stack: u64,
+}
+
+impl Procedures {
+    /// Where the stack starts, growing down from there.
+    fn stack_top(self) -> u64 {
+        self.stack + CALL_STACK_LENGTH
+    }
+
+    /// Whether the current cell can hold a number past the end of the
+    /// table.
+    fn can_overflow(tape: Tape) -> bool {
+        tape.width.max() >= PROCEDURE_COUNT
+    }
+}
+
+/// Records the procedure starting at `body` under the number in the current
+/// cell, then skips it.
+fn emit_define_proc(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    procedures: Procedures,
+    errors: &ErrorRoutines,
+    skip: CodeLabel,
+    body: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    emit_load_cell(a, tape)?;
+    if Procedures::can_overflow(tape) {
+        a.cmp(asm::rax, PROCEDURE_COUNT as i32)?;
+        a.jae(errors.get(RuntimeError::ProcedureNumber))?;
+    }
+    a.lea(asm::rdx, asm::ptr(*body))?;
+    a.mov(
+        asm::qword_ptr(asm::rax * 8 + procedures.table as i64),
+        asm::rdx,
+    )?;
+    a.jmp(skip)?;
+
+    a.set_label(body)?;
+
+    Ok(())
+}
+
+fn emit_end_proc(
+    a: &mut CodeAssembler,
+    position: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    a.ret()?;
+
+    a.set_label(position)?;
+
+    Ok(())
+}
+
+/// Calls the procedure numbered by the current cell, as long as it's defined
+/// and the stack has room for another call.
+fn emit_call_proc(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    procedures: Procedures,
+    errors: &ErrorRoutines,
+) -> Result<(), IcedError> {
+    let limit = procedures.stack_top() - 8 * CALL_DEPTH_LIMIT as u64;
+    let undefined = errors.get(RuntimeError::UndefinedProcedure);
+
+    emit_load_cell(a, tape)?;
+    if Procedures::can_overflow(tape) {
+        a.cmp(asm::rax, PROCEDURE_COUNT as i32)?;
+        a.jae(undefined)?;
+    }
+    a.mov(
+        asm::rax,
+        asm::qword_ptr(asm::rax * 8 + procedures.table as i64),
+    )?;
+    a.test(asm::rax, asm::rax)?;
+    a.jz(undefined)?;
+    a.cmp(asm::rsp, limit as i32)?;
+    a.jbe(errors.get(RuntimeError::CallDepth))?;
+    a.call(asm::rax)?;
+
+    Ok(())
+}
+
+/// Forks the process. The parent zeroes the current cell, and the child
+/// moves right and sets the cell it lands on to 1.
+fn emit_fork(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    errors: &ErrorRoutines,
+) -> Result<(), IcedError> {
+    let mut child = a.create_label();
+    let mut done = a.create_label();
+
+    a.mov(asm::r15, asm::rcx)?;
+    a.mov(asm::rax, 57u64)?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+    a.test(asm::rax, asm::rax)?;
+    a.jz(child)?;
+    a.js(errors.get(RuntimeError::Fork))?;
+    a.mov(tape.cell(asm::rcx + 0), 0)?;
+    a.jmp(done)?;
+
+    a.set_label(&mut child)?;
+    emit_shift_right(a, tape, 1)?;
+    a.mov(tape.cell(asm::rcx + 0), 1)?;
+
+    a.zero_bytes()?;
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+/// Emits a routine to call for `%`, which gathers the arguments laid out
+/// from the current cell into `args`, makes the syscall and stores its result
+/// in the current cell. It leaves the pointer in rcx as it was.
+fn emit_syscall(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    args: u64,
+    errors: &ErrorRoutines,
+    entry: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    let mut argument = a.create_label();
+    let mut digit = a.create_label();
+    let mut number = a.create_label();
+    let mut buffer = a.create_label();
+    let mut store = a.create_label();
+    let mut call = a.create_label();
+
+    let width = tape.width.bytes();
+
+    // moves r8 on by `by` bytes, back to the start if it reaches the end
+    let wrap = |a: &mut CodeAssembler, by| -> Result<(), IcedError> {
+        a.lea(asm::r8, asm::r8 + by)?;
+        a.lea(asm::rsi, asm::r8 - tape.len())?;
+        a.cmp(asm::r8d, tape.end())?;
+        a.cmovae(asm::r8, asm::rsi)?;
+        Ok(())
+    };
+    // reads the cell at r8 into rdx and moves r8 on to the next one
+    let next = |a: &mut CodeAssembler| -> Result<(), IcedError> {
+        match tape.width {
+            CellWidth::U8 => a.movzx(asm::edx, asm::byte_ptr(asm::r8))?,
+            CellWidth::U16 => a.movzx(asm::edx, asm::word_ptr(asm::r8))?,
+            CellWidth::U32 => a.mov(asm::edx, asm::dword_ptr(asm::r8))?,
+            CellWidth::U64 => a.mov(asm::rdx, asm::qword_ptr(asm::r8))?,
+        }
+        wrap(a, width as i32)
+    };
+
+    a.set_label(entry)?;
+    a.mov(asm::r15, asm::rcx)?;
+    a.xor(asm::eax, asm::eax)?;
+    for i in 0..SYSCALL_ARGUMENTS as u64 {
+        a.mov(asm::qword_ptr(args + 8 * i), asm::rax)?;
+    }
+
+    // r8 walks the cells after the syscall number, r9 counts down the
+    // arguments left and rdi is where the next one goes
+    a.mov(asm::r8, asm::rcx)?;
+    wrap(a, width as i32)?;
+    next(a)?;
+    a.cmp(asm::rdx, SYSCALL_ARGUMENTS as i32)?;
+    a.ja(errors.get(RuntimeError::SyscallArguments))?;
+    a.mov(asm::r9, asm::rdx)?;
+    a.mov(asm::rdi, args)?;
+
+    // each argument's kind goes in r10 and its length in r11
+    a.set_label(&mut argument)?;
+    a.test(asm::r9, asm::r9)?;
+    a.jz(call)?;
+    next(a)?;
+    a.mov(asm::r10, asm::rdx)?;
+    next(a)?;
+    a.mov(asm::r11, asm::rdx)?;
+    a.cmp(asm::r10, 1)?;
+    a.je(buffer)?;
+    a.cmp(asm::r10, 2)?;
+    a.ja(errors.get(RuntimeError::SyscallArgumentKind))?;
+
+    // the number its cells spell out, most significant first
+    a.xor(asm::eax, asm::eax)?;
+    a.set_label(&mut digit)?;
+    a.test(asm::r11, asm::r11)?;
+    a.jz(number)?;
+    next(a)?;
+    match tape.width {
+        CellWidth::U64 => a.mov(asm::rax, asm::rdx)?,
+        _ => {
+            a.shl(asm::rax, tape.width.bits())?;
+            a.or(asm::rax, asm::rdx)?;
+        }
+    }
+    a.dec(asm::r11)?;
+    a.jmp(digit)?;
+    a.set_label(&mut number)?;
+    a.cmp(asm::r10, 2)?;
+    a.jne(store)?;
+    // kind 2 takes the number as the index of a cell
+    a.xor(asm::edx, asm::edx)?;
+    a.mov(asm::esi, CELL_BUFFER_LENGTH)?;
+    a.div(asm::rsi)?;
+    a.lea(asm::rax, asm::rdx * width + tape.base as i32)?;
+    a.jmp(store)?;
+
+    // kind 1 passes its cells where they are, if they don't wrap
+    a.set_label(&mut buffer)?;
+    a.mov(asm::eax, tape.end())?;
+    a.sub(asm::rax, asm::r8)?;
+    a.shr(asm::rax, width.trailing_zeros())?;
+    a.cmp(asm::r11, asm::rax)?;
+    a.ja(errors.get(RuntimeError::SyscallBuffer))?;
+    a.mov(asm::rax, asm::r8)?;
+    a.lea(asm::r8, asm::r8 + asm::r11 * width)?;
+    wrap(a, 0)?;
+
+    a.set_label(&mut store)?;
+    a.mov(asm::qword_ptr(asm::rdi), asm::rax)?;
+    a.add(asm::rdi, 8)?;
+    a.dec(asm::r9)?;
+    a.jmp(argument)?;
+
+    a.set_label(&mut call)?;
+    a.mov(asm::rcx, asm::r15)?;
+    emit_load_cell(a, tape)?;
+    a.mov(asm::rdi, asm::qword_ptr(args))?;
+    a.mov(asm::rsi, asm::qword_ptr(args + 8))?;
+    a.mov(asm::rdx, asm::qword_ptr(args + 16))?;
+    a.mov(asm::r10, asm::qword_ptr(args + 24))?;
+    a.mov(asm::r8, asm::qword_ptr(args + 32))?;
+    a.mov(asm::r9, asm::qword_ptr(args + 40))?;
+    a.syscall()?;
+    a.mov(asm::rcx, asm::r15)?;
+    match tape.width {
+        CellWidth::U8 => a.mov(asm::byte_ptr(asm::rcx), RAX.r8)?,
+        CellWidth::U16 => a.mov(asm::word_ptr(asm::rcx), RAX.r16)?,
+        CellWidth::U32 => a.mov(asm::dword_ptr(asm::rcx), RAX.r32)?,
+        CellWidth::U64 => a.mov(asm::qword_ptr(asm::rcx), RAX.r64)?,
+    }
+    a.ret()?;
+
+    Ok(())
+}
+
+/// Writes `len` bytes at `message` to stderr and exits with status 1.
+fn emit_runtime_error(
+    a: &mut CodeAssembler,
+    message: u64,
+    len: usize,
+    entry: &mut CodeLabel,
+) -> Result<(), IcedError> {
+    a.set_label(entry)?;
+    a.mov(asm::rax, 1u64)?;
+    a.mov(asm::rdi, 2u64)?;
+    a.mov(asm::rsi, message)?;
+    a.mov(asm::rdx, len as u64)?;
+    a.syscall()?;
+    a.mov(asm::rax, 60u64)?;
+    a.mov(asm::rdi, 1u64)?;
+    a.syscall()?;
+
+    Ok(())
+}
+
+fn emit_cell_op(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    cell: AsmMemoryOperand,
+    instr: &Instruction,
+    input: Input,
+) -> Result<(), IcedError> {
+    use Instruction as I;
+    match *instr {
+        I::Add { amount, .. } => emit_add(a, tape, cell, amount),
+        I::Sub { amount, .. } => emit_sub(a, tape, cell, amount),
+        I::Set { value, .. } => emit_set(a, tape, cell, value),
+        I::Read { .. } => emit_read(a, tape, cell, input),
+        I::Write { .. } => emit_write(a, cell),
+        _ => unreachable!("{instr:?} doesn't work on a cell"),
+    }
+}
+
+/// Emits straight-line code working on cells around the pointer. When every
+/// cell it touches is inside the tape the code addresses them directly off
+/// the pointer, otherwise it falls back to wrapping each address.
+fn emit_cell_block(
+    a: &mut CodeAssembler,
+    tape: Tape,
+    block: &[Spanned<Instruction>],
+    input: Input,
+) -> Result<(), IcedError> {
+    let offsets = || {
+        block
+            .iter()
+            .filter_map(|i| i.inner.offset())
+            .map(|o| tape.distance(o))
+    };
+    let min = offsets().min().unwrap_or(0);
+    let max = offsets().max().unwrap_or(0);
 
-impl SegmentBuilder for DataSegment {
-    fn code(
-        &self,
-        _labels: &LabelMap,
-    ) -> Result<super::elf::Segment, CompilerError> {
+    if min == 0 && max == 0 {
+        for instr in block {
+            emit_cell_op(a, tape, asm::rcx + 0, &instr.inner, input)?;
+        }
+
+        return Ok(());
+    }
+
+    let mut wrapping = a.create_label();
+    let mut done = a.create_label();
+
+    a.cmp(asm::ecx, (tape.base as i64 - min) as u32)?;
+    a.jb(wrapping)?;
+    a.cmp(asm::ecx, (tape.end() as i64 - max) as u32)?;
+    a.jae(wrapping)?;
+
+    for (instr, distance) in block.iter().zip(offsets()) {
+        emit_cell_op(a, tape, asm::rcx + distance, &instr.inner, input)?;
+    }
+    a.jmp(done)?;
+
+    a.set_label(&mut wrapping)?;
+    for instr in block {
+        let cell = match instr.inner.offset() {
+            Some(offset) if tape.distance(offset) != 0 => {
+                emit_wrapped_address(a, tape, offset)?;
+                asm::rdx + 0
+            }
+            _ => asm::rcx + 0,
+        };
+        emit_cell_op(a, tape, cell, &instr.inner, input)?;
+    }
+
+    // a read can leave its own label waiting for the next instruction
+    a.zero_bytes()?;
+    a.set_label(&mut done)?;
+
+    Ok(())
+}
+
+struct DataSegment<'a> {
+    cell_width: CellWidth,
+    /// Where the program got to at compile time, if it was run.
+    state: Option<&'a State>,
+    /// Whether the program dumps the tape, and so needs room to format it.
+    debug: bool,
+    /// Embedded input that wasn't read at compile time.
+    input: &'a [u8],
+    /// Whether the program makes syscalls, and so needs room to gather their
+    /// arguments.
+    syscalls: bool,
+    /// Errors the program could run into, whose messages it needs.
+    errors: &'a [RuntimeError],
+}
+
+impl SegmentBuilder for DataSegment<'_> {
+    fn code(&self, _labels: &LabelMap) -> Result<Segment, CompilerError> {
         let mut a = CodeAssembler::new(64)?;
 
+        let bytes = self.cell_width.bytes() as usize;
+        let cells = match self.state {
+            Some(state) => state
+                .tape
+                .iter()
+                .flat_map(|cell| cell.to_le_bytes().into_iter().take(bytes))
+                .collect(),
+            None => vec![0u8; CELL_BUFFER_LENGTH as usize * bytes],
+        };
+
+        let mut output = a.create_label();
+        a.set_label(&mut output)?;
+        if let Some(state) = self.state.filter(|s| !s.output.is_empty()) {
+            a.db(&state.output)?;
+        }
+
         let mut cell_buffer = a.create_label();
+        a.db(&[0u8; CELL_BUFFER_PADDING])?;
         a.set_label(&mut cell_buffer)?;
-        a.db(&[0u8; CELL_BUFFER_LENGTH as usize])?;
+        a.db(&cells)?;
+        a.db(&[0u8; CELL_BUFFER_PADDING])?;
+
+        let mut labels = vec![("output", output), ("cell_buffer", cell_buffer)];
+        if !self.input.is_empty() {
+            let mut input_cursor = a.create_label();
+            let mut input = a.create_label();
+            a.set_label(&mut input_cursor)?;
+            a.db(&0u64.to_le_bytes())?;
+            a.set_label(&mut input)?;
+            a.db(self.input)?;
+            labels.push(("input_cursor", input_cursor));
+            labels.push(("input", input));
+        }
+        if self.debug {
+            let mut debug_buffer = a.create_label();
+            a.set_label(&mut debug_buffer)?;
+            a.db(&[0u8; DUMP_BUFFER_LENGTH])?;
+            labels.push(("debug_buffer", debug_buffer));
+        }
+        if self.syscalls {
+            let mut syscall_args = a.create_label();
+            a.set_label(&mut syscall_args)?;
+            a.db(&[0u8; 8 * SYSCALL_ARGUMENTS])?;
+            labels.push(("syscall_args", syscall_args));
+        }
+        for error in self.errors {
+            let mut message = a.create_label();
+            a.set_label(&mut message)?;
+            a.db(error.message().as_bytes())?;
+            labels.push((error.label(), message));
+        }
 
-        Ok(segment!(a, cell_buffer))
+        Ok(Segment::new(a, labels))
     }
 
     fn flags(&self) -> PhdrFlags {
@@ -143,15 +1285,38 @@ impl SegmentBuilder for DataSegment {
     }
 }
 
-struct TextSegment {
+/// The table of procedures, followed by room for the call stack.
+struct ProcedureSegment;
+
+impl SegmentBuilder for ProcedureSegment {
+    fn code(&self, _labels: &LabelMap) -> Result<Segment, CompilerError> {
+        let mut a = CodeAssembler::new(64)?;
+
+        // the call stack starts right after the table
+        let mut procedure_table = a.create_label();
+        a.set_label(&mut procedure_table)?;
+        a.db(&vec![0u8; 8 * PROCEDURE_COUNT as usize])?;
+
+        Ok(segment!(a, procedure_table))
+    }
+
+    fn flags(&self) -> PhdrFlags {
+        PhdrFlags::R | PhdrFlags::W
+    }
+
+    fn reserved(&self) -> u64 {
+        CALL_STACK_LENGTH
+    }
+}
+
+struct TextSegment<'a> {
     instructions: IR,
+    options: CompilerOptions,
+    state: Option<&'a State>,
 }
 
-impl SegmentBuilder for TextSegment {
-    fn code(
-        &self,
-        labels: &LabelMap,
-    ) -> Result<super::elf::Segment, CompilerError> {
+impl SegmentBuilder for TextSegment<'_> {
+    fn code(&self, labels: &LabelMap) -> Result<Segment, CompilerError> {
         let mut a = CodeAssembler::new(64)?;
 
         let mut _start = a.create_label();
@@ -159,55 +1324,207 @@ impl SegmentBuilder for TextSegment {
 
         // setup
         let buffer_start = labels.get("cell_buffer")?;
-        a.mov(asm::rcx, buffer_start)?;
+        let tape = Tape {
+            base: buffer_start as u32,
+            width: self.instructions.cell_width,
+        };
+
+        let instrs = &self.instructions.instructions;
+
+        let read = self.state.map_or(0, |state| state.input);
+        let embedded = match self.instructions.input.len() - read {
+            0 => None,
+            len => Some(EmbeddedInput {
+                base: labels.get("input")?,
+                len,
+                cursor: labels.get("input_cursor")?,
+            }),
+        };
+        let input = Input {
+            eof: self.options.eof,
+            embedded,
+        };
+
+        // anything already run at compile time
+        let mut resume = a.create_label();
+        let resume_pc = self.state.map(|state| state.pc);
+        if let Some(state) = self.state {
+            if !state.output.is_empty() {
+                let output = labels.get("output")?;
+                emit_output(&mut a, output, state.output.len())?;
+            }
+
+            if state.pc >= instrs.len() {
+                emit_exit(&mut a)?;
+
+                return Ok(segment!(a, _start));
+            }
+        }
+
+        let procedures = if uses_procedures(&self.instructions) {
+            let table = labels.get("procedure_table")?;
+            Some(Procedures {
+                table,
+                stack: table + 8 * PROCEDURE_COUNT,
+            })
+        } else {
+            None
+        };
+        if let Some(procedures) = procedures {
+            a.mov(asm::rsp, procedures.stack_top())?;
+        }
+        let mut errors = ErrorRoutines(
+            RuntimeError::possible(&self.instructions)
+                .into_iter()
+                .map(|error| (error, a.create_label()))
+                .collect(),
+        );
+
+        let pointer = self.state.map_or(0, |state| state.pointer as i64);
+        a.mov(asm::rcx, buffer_start + tape.distance(pointer) as u64)?;
+        if self.state.is_some() {
+            a.jmp(resume)?;
+        }
 
         let mut jump_labels: HashMap<u64, CodeLabel> = self
             .instructions
-            .0
+            .instructions
             .iter()
             .enumerate()
-            .filter_map(|(c, i)| match i {
-                Instruction::JumpForward(_) | Instruction::JumpBackward(_) => {
-                    Some((c as u64, a.create_label()))
-                }
+            .filter_map(|(c, i)| match i.inner {
+                Instruction::JumpForward(_)
+                | Instruction::JumpBackward(_)
+                | Instruction::DefineProc(_)
+                | Instruction::EndProc(_) => Some((c as u64, a.create_label())),
                 _ => None,
             })
             .collect();
 
-        for (i, instr) in self.instructions.0.iter().enumerate() {
+        let is_mul_add = |i: usize| {
+            instrs
+                .get(i)
+                .is_some_and(|i| matches!(i.inner, Instruction::MulAdd { .. }))
+        };
+
+        // a run of MulAdds shares a single check of the current cell
+        let mut mul_add_skip = a.create_label();
+
+        // every `#` calls the same routine, emitted after the program
+        let mut debug_dump = a.create_label();
+        // and every `%` the same syscall routine
+        let mut syscall = a.create_label();
+
+        let mut i = 0;
+        while i < instrs.len() {
             use Instruction as I;
-            match instr {
+
+            if resume_pc == Some(i) {
+                // the previous instruction may already have a label waiting
+                a.zero_bytes()?;
+                a.set_label(&mut resume)?;
+            }
+
+            // a block can't run over the point the program resumes at
+            let block_len = instrs[i..]
+                .iter()
+                .enumerate()
+                .take_while(|&(j, instr)| {
+                    instr.inner.offset().is_some()
+                        && (j == 0 || resume_pc != Some(i + j))
+                })
+                .count();
+            if block_len > 0 {
+                emit_cell_block(
+                    &mut a,
+                    tape,
+                    &instrs[i..i + block_len],
+                    input,
+                )?;
+                i += block_len;
+                continue;
+            }
+
+            match &instrs[i].inner {
                 I::ShiftLeft(v) => {
                     let v: u32 =
                         (v % CELL_BUFFER_LENGTH as u64).try_into().unwrap();
-                    emit_shift_left(&mut a, buffer_start as u32, v)?
+                    emit_shift_left(&mut a, tape, v)?
                 }
                 I::ShiftRight(v) => {
                     let v: u32 =
                         (v % CELL_BUFFER_LENGTH as u64).try_into().unwrap();
-                    emit_shift_right(&mut a, buffer_start as u32, v)?
+                    emit_shift_right(&mut a, tape, v)?
+                }
+                I::MulAdd { offset, factor } => {
+                    if i == 0 || !is_mul_add(i - 1) {
+                        mul_add_skip = a.create_label();
+                        emit_mul_add_entry(&mut a, tape, mul_add_skip)?;
+                    }
+                    emit_mul_add(&mut a, tape, *offset, *factor)?;
+                    if !is_mul_add(i + 1) {
+                        a.set_label(&mut mul_add_skip)?;
+                    }
                 }
-                I::Add(v) => emit_add(&mut a, *v)?,
-                I::Sub(v) => emit_sub(&mut a, *v)?,
-                I::Read => todo!(),
-                I::Write => emit_write(&mut a)?,
+                I::Scan { stride } => emit_scan(&mut a, tape, *stride)?,
                 I::JumpForward(v) => {
                     let target = *jump_labels.get(v).unwrap();
                     let position = jump_labels.get_mut(&(i as u64)).unwrap();
-                    emit_jump_forward(&mut a, target, position)?;
+                    emit_jump_forward(&mut a, tape, target, position)?;
                 }
                 I::JumpBackward(v) => {
                     let target = *jump_labels.get(v).unwrap();
                     let position = jump_labels.get_mut(&(i as u64)).unwrap();
-                    emit_jump_backward(&mut a, target, position)?;
+                    emit_jump_backward(&mut a, tape, target, position)?;
                 }
+                I::Debug => a.call(debug_dump)?,
+                I::DefineProc(v) => {
+                    let procedures = procedures.expect("procedures are used");
+                    let target = *jump_labels.get(v).unwrap();
+                    let position = jump_labels.get_mut(&(i as u64)).unwrap();
+                    emit_define_proc(
+                        &mut a, tape, procedures, &errors, target, position,
+                    )?;
+                }
+                I::EndProc(_) => {
+                    let position = jump_labels.get_mut(&(i as u64)).unwrap();
+                    emit_end_proc(&mut a, position)?;
+                }
+                I::CallProc => {
+                    let procedures = procedures.expect("procedures are used");
+                    emit_call_proc(&mut a, tape, procedures, &errors)?;
+                }
+                I::Fork => emit_fork(&mut a, tape, &errors)?,
+                I::Syscall => a.call(syscall)?,
+                I::Add { .. }
+                | I::Sub { .. }
+                | I::Set { .. }
+                | I::Read { .. }
+                | I::Write { .. } => unreachable!(),
             }
+
+            i += 1;
         }
 
         // end!
-        a.mov(asm::rax, 60u64)?;
-        a.mov(asm::rdi, 0u64)?;
-        a.syscall()?;
+        if instrs.iter().any(|i| i.inner == Instruction::Fork) {
+            emit_wait_for_forks(&mut a)?;
+        }
+        emit_exit(&mut a)?;
+
+        if instrs.iter().any(|i| i.inner == Instruction::Debug) {
+            let buffer = labels.get("debug_buffer")?;
+            emit_debug_dump(&mut a, tape, buffer, &mut debug_dump)?;
+        }
+        if instrs.iter().any(|i| i.inner == Instruction::Syscall) {
+            let args = labels.get("syscall_args")?;
+            emit_syscall(&mut a, tape, args, &errors, &mut syscall)?;
+        }
+
+        for (error, entry) in &mut errors.0 {
+            let message = labels.get(error.label())?;
+            let len = error.message().len();
+            emit_runtime_error(&mut a, message, len, entry)?;
+        }
 
         Ok(segment!(a, _start))
     }
@@ -217,8 +1534,56 @@ impl SegmentBuilder for TextSegment {
     }
 }
 
+fn uses_procedures(ir: &IR) -> bool {
+    ir.instructions.iter().any(|i| {
+        matches!(
+            i.inner,
+            Instruction::DefineProc(_)
+                | Instruction::EndProc(_)
+                | Instruction::CallProc
+        )
+    })
+}
+
 pub fn compile(ir: IR) -> Result<Vec<u8>, CompilerError> {
-    let ts = TextSegment { instructions: ir };
+    compile_with_options(ir, CompilerOptions::default())
+}
+
+pub fn compile_with_options(
+    ir: IR,
+    options: CompilerOptions,
+) -> Result<Vec<u8>, CompilerError> {
+    let state = options.fuel.map(|fuel| evaluate(&ir, fuel));
+    let read = state.as_ref().map_or(0, |state| state.input);
+    let input = ir.input[read..].to_vec();
+    let errors = RuntimeError::possible(&ir);
+
+    let ds = DataSegment {
+        cell_width: ir.cell_width,
+        state: state.as_ref(),
+        debug: ir
+            .instructions
+            .iter()
+            .any(|i| i.inner == Instruction::Debug),
+        input: &input,
+        syscalls: ir
+            .instructions
+            .iter()
+            .any(|i| i.inner == Instruction::Syscall),
+        errors: &errors,
+    };
+    let uses_procedures = uses_procedures(&ir);
+    let ts = TextSegment {
+        instructions: ir,
+        options,
+        state: state.as_ref(),
+    };
+
+    let mut segments: Vec<&dyn SegmentBuilder> = vec![&ds];
+    if uses_procedures {
+        segments.push(&ProcedureSegment);
+    }
+    segments.push(&ts);
 
-    compile_to_elf(&[&DataSegment, &ts])
+    compile_to_elf(&segments)
 }
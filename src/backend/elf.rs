@@ -41,7 +41,7 @@ impl BinaryBuilder {
     }
 
     fn pad(&mut self, count: usize) {
-        self.binary.extend(iter::repeat(0).take(count));
+        self.binary.extend(iter::repeat_n(0, count));
     }
 
     fn pad_to_width(&mut self, width: usize) {
@@ -128,6 +128,12 @@ pub trait SegmentBuilder {
 
     fn flags(&self) -> PhdrFlags;
 
+    /// Zeroed bytes to reserve right after the segment's code, which take up
+    /// no room in the file.
+    fn reserved(&self) -> u64 {
+        0
+    }
+
     fn build(
         &self,
         ip: u64,
@@ -206,17 +212,21 @@ pub fn compile_to_elf(
 
     // === SEGMENTS ===
     let mut labels = LabelMap(HashMap::new());
+    // reserved memory pushes every later segment further along in memory
+    // than it is in the file
+    let mut reserved = 0;
     for (seg, patches) in segments.iter().zip(seg_patches) {
         let [offset, vaddr, file_size, mem_size] = patches;
 
         let file_offset = b.current_addr() as u64;
-        let vmem_offset = file_offset + LOAD_POS;
+        let vmem_offset = file_offset + LOAD_POS + reserved;
         let source = seg.build(vmem_offset, &mut labels)?;
 
         b.patch(offset, file_offset);
         b.patch(vaddr, vmem_offset);
         b.patch(file_size, source.len() as u64);
-        b.patch(mem_size, source.len() as u64);
+        b.patch(mem_size, source.len() as u64 + seg.reserved());
+        reserved += seg.reserved().next_multiple_of(PAGE_SIZE);
 
         b.emit_slice(&source[..]);
         b.pad_to_width(PAGE_SIZE as usize);
@@ -0,0 +1,72 @@
+use std::io;
+
+use crate::{
+    frontend::parser::{Instruction, IR},
+    interp::{Execution, Machine},
+};
+
+use super::compiler::EofBehavior;
+
+/// The machine part way through running a program.
+#[derive(Clone, Debug)]
+pub(crate) struct State {
+    pub tape: Vec<u64>,
+    /// Index of the current cell.
+    pub pointer: usize,
+    /// Index of the next instruction to run.
+    pub pc: usize,
+    /// How much of the embedded input has been read.
+    pub input: usize,
+    pub output: Vec<u8>,
+}
+
+/// Runs the program from the start for at most roughly `fuel` steps, or until
+/// it first reads from stdin, dumps the tape, forks or makes a syscall, which
+/// have to happen at run time. It only ever stops where the compiled code can
+/// pick up again, so never in the middle of a run of `MulAdd`s, and never once
+/// a procedure has been defined, since the compiled code keeps track of those
+/// itself.
+pub(crate) fn evaluate(ir: &IR, mut fuel: u64) -> State {
+    use Instruction as I;
+
+    let instrs = &ir.instructions;
+    let mut machine = Machine::new(ir, EofBehavior::default());
+
+    while let Some(instr) = machine.next_instruction() {
+        let pc = machine.pc();
+        let resumable = !(matches!(instr, I::MulAdd { .. })
+            && pc > 0
+            && matches!(instrs[pc - 1].inner, I::MulAdd { .. }));
+        let reads_stdin = matches!(instr, I::Read { .. })
+            && machine.embedded_input().is_empty();
+        let runtime_only = matches!(
+            instr,
+            I::Debug | I::DefineProc(_) | I::CallProc | I::Fork | I::Syscall
+        );
+        if reads_stdin || runtime_only || fuel == 0 && resumable {
+            break;
+        }
+        fuel = fuel.saturating_sub(1);
+
+        machine
+            .step(&mut io::empty(), &mut io::sink())
+            .expect("stdin isn't read, and writing to a sink can't fail");
+    }
+
+    let pc = machine.pc();
+    let input = ir.input.len() - machine.embedded_input().len();
+    let Execution {
+        output,
+        tape,
+        pointer,
+        ..
+    } = machine.into_execution();
+
+    State {
+        tape,
+        pointer,
+        pc,
+        input,
+        output,
+    }
+}
@@ -1,2 +1,4 @@
+pub mod bits;
 pub mod compiler;
 pub mod elf;
+mod eval;
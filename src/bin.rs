@@ -1,6 +1,9 @@
 use std::{fs::File, io::Write};
 
-use concussion::{backend::compiler::compile, frontend::parser::IR};
+use concussion::{
+    backend::compiler::compile,
+    frontend::{optimizer::optimize, parser::IR},
+};
 
 fn main() {
     let source = r#"
@@ -152,7 +155,13 @@ fn main() {
     "#;
 
     let p = source.into();
-    let p = IR::parse(&p).unwrap();
+    let (p, dead) = optimize(IR::parse(&p).unwrap());
+    if dead.loops > 0 {
+        eprintln!(
+            "removed {} dead loops, {} instructions",
+            dead.loops, dead.instructions
+        );
+    }
     let asm = compile(p).unwrap();
 
     let mut file = File::create("foo").unwrap();
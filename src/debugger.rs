@@ -0,0 +1,285 @@
+//! Steps through [`IR`] on the [interpreter](crate::interp), stopping at
+//! breakpoints set on source positions and when watched cells change. Every
+//! stop is reported in terms of the original source.
+
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+};
+
+use thiserror::Error;
+
+use crate::{
+    backend::compiler::EofBehavior,
+    frontend::{
+        parser::{Instruction, Program, IR, TAPE_LENGTH},
+        span::Snippet,
+    },
+    interp::{InterpError, Machine, Status},
+};
+
+#[derive(Error, Debug)]
+pub enum DebugError {
+    #[error(transparent)]
+    Interp(#[from] InterpError),
+    #[error("no code at or after {line}:{col}")]
+    NoCode { line: usize, col: usize },
+    #[error("cell {0} is past the end of the tape")]
+    NoSuchCell(usize),
+}
+
+/// Why the debugger stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The requested step is done.
+    Step,
+    Breakpoint,
+    /// A watched cell changed value.
+    Watchpoint {
+        cell: usize,
+        old: u64,
+        new: u64,
+    },
+    /// There's nothing left to run.
+    Finished,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stop {
+    pub reason: StopReason,
+    /// Index of the next instruction to run.
+    pub pc: usize,
+    /// Where the next instruction came from, or `None` once finished.
+    pub location: Option<Snippet>,
+}
+
+/// The tape and pointer as of the last stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot<'a> {
+    pub tape: &'a [u64],
+    pub pointer: usize,
+}
+
+impl Snapshot<'_> {
+    /// The cells `radius` either side of the pointer, wrapping around the
+    /// ends of the tape like the pointer does.
+    pub fn window(&self, radius: usize) -> Vec<u64> {
+        let len = self.tape.len();
+        let start = self.pointer + len - radius % len;
+
+        (0..2 * radius + 1)
+            .map(|i| self.tape[(start + i) % len])
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Step,
+    /// Run until the instruction at `pc` is next, with `depth` procedure
+    /// calls in progress.
+    Until {
+        pc: usize,
+        depth: usize,
+    },
+    Continue,
+}
+
+pub struct Debugger<'a> {
+    program: &'a Program,
+    machine: Machine<'a>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    /// Debugs `ir`, which has to have been built from `program` so its spans
+    /// point into it.
+    pub fn new(program: &'a Program, ir: &'a IR, eof: EofBehavior) -> Self {
+        Debugger {
+            program,
+            machine: Machine::new(ir, eof),
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            tape: self.machine.tape(),
+            pointer: self.machine.pointer(),
+        }
+    }
+
+    /// Where the next instruction came from, or `None` once finished.
+    pub fn location(&self) -> Option<Snippet> {
+        let instr = self.machine.ir().instructions.get(self.machine.pc())?;
+        Some(self.program.snippet(instr.span))
+    }
+
+    /// Sets a breakpoint on the instruction covering a 1-based line and
+    /// column. Optimized code can fold several commands into one
+    /// instruction, so if nothing covers it exactly, the breakpoint goes on
+    /// the first instruction after it. Returns the index of that
+    /// instruction.
+    pub fn add_breakpoint(
+        &mut self,
+        line: usize,
+        col: usize,
+    ) -> Result<usize, DebugError> {
+        let no_code = || DebugError::NoCode { line, col };
+        let offset = self.program.offset(line, col).ok_or_else(no_code)?;
+        let instrs = &self.machine.ir().instructions;
+
+        let covering = instrs.iter().position(|i| {
+            (i.span.offset..i.span.end().max(i.span.offset + 1))
+                .contains(&offset)
+        });
+        let after = || {
+            instrs
+                .iter()
+                .enumerate()
+                .filter(|(_, i)| i.span.offset >= offset)
+                .min_by_key(|(_, i)| i.span.offset)
+                .map(|(pc, _)| pc)
+        };
+        let pc = covering.or_else(after).ok_or_else(no_code)?;
+
+        self.breakpoints.insert(pc);
+        Ok(pc)
+    }
+
+    /// Removes the breakpoint on the instruction at `pc`, returning whether
+    /// there was one.
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops whenever the value of `cell` changes.
+    pub fn watch(&mut self, cell: usize) -> Result<(), DebugError> {
+        if cell >= TAPE_LENGTH as usize {
+            return Err(DebugError::NoSuchCell(cell));
+        }
+
+        self.watchpoints.insert(cell);
+        Ok(())
+    }
+
+    /// Stops watching `cell`, returning whether it was watched.
+    pub fn unwatch(&mut self, cell: usize) -> bool {
+        self.watchpoints.remove(&cell)
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Runs the next instruction. A scan runs until it finds its zero.
+    pub fn step(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Stop, DebugError> {
+        self.advance(input, output, Mode::Step)
+    }
+
+    /// Runs the next instruction, or the whole loop if it's the start of
+    /// one, or the whole procedure if it's a call. Breakpoints and
+    /// watchpoints inside them still stop it early.
+    pub fn step_over(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Stop, DebugError> {
+        let depth = self.machine.call_depth();
+        let mode = match self.machine.next_instruction() {
+            Some(Instruction::JumpForward(end)) => Mode::Until {
+                pc: end as usize + 1,
+                depth,
+            },
+            Some(Instruction::CallProc) => Mode::Until {
+                pc: self.machine.pc() + 1,
+                depth,
+            },
+            _ => Mode::Step,
+        };
+
+        self.advance(input, output, mode)
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or the program
+    /// finishes. A breakpoint on the instruction it's already stopped at
+    /// doesn't count.
+    pub fn resume(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Stop, DebugError> {
+        self.advance(input, output, Mode::Continue)
+    }
+
+    fn advance(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        mode: Mode,
+    ) -> Result<Stop, DebugError> {
+        loop {
+            let pc = self.machine.pc();
+            let watched: Vec<_> = self
+                .watchpoints
+                .iter()
+                .map(|&cell| (cell, self.machine.tape()[cell]))
+                .collect();
+
+            let status = self.machine.step(input, output)?;
+
+            for (cell, old) in watched {
+                let new = self.machine.tape()[cell];
+                if new != old {
+                    let reason = StopReason::Watchpoint { cell, old, new };
+                    return Ok(self.stop(reason));
+                }
+            }
+
+            if status == Status::Finished {
+                return Ok(self.stop(StopReason::Finished));
+            }
+
+            let next = self.machine.pc();
+            if next == pc {
+                // part way through a scan
+                continue;
+            }
+
+            match mode {
+                Mode::Step => return Ok(self.stop(StopReason::Step)),
+                Mode::Until { pc, depth }
+                    if next == pc && self.machine.call_depth() == depth =>
+                {
+                    return Ok(self.stop(StopReason::Step));
+                }
+                _ if self.breakpoints.contains(&next) => {
+                    return Ok(self.stop(StopReason::Breakpoint));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn stop(&self, reason: StopReason) -> Stop {
+        Stop {
+            reason,
+            pc: self.machine.pc(),
+            location: self.location(),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Boolfuck, Brainfuck on a tape of single bits. `+` flips the current bit,
+//! `,` reads a bit and `;` writes one, each byte of input and output going
+//! least significant bit first. Source is read with
+//! [`TokenTable::boolfuck`](super::tokens::TokenTable::boolfuck), then lowered
+//! from the [`IR`] it parses to into a [`BitIR`].
+
+use super::{
+    parser::{Instruction, ParseError, Program, IR, TAPE_LENGTH},
+    span::{Diagnostic, Spanned},
+};
+
+/// How many bits are on the tape, which takes up the same memory as a tape
+/// of bytes.
+pub const BIT_TAPE_LENGTH: u64 = 8 * TAPE_LENGTH as u64;
+
+/// A single operation on the bit tape.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitInstruction {
+    /// Moves the pointer right by this many bits, or left if it's negative,
+    /// by less than a whole lap of the tape.
+    Shift(i64),
+    Flip,
+    Read,
+    Write,
+    JumpForward(u64),
+    JumpBackward(u64),
+}
+
+#[derive(Clone, Debug)]
+pub struct BitIR {
+    pub instructions: Vec<Spanned<BitInstruction>>,
+}
+
+impl BitIR {
+    /// Parses a program, which can't use anything a bit tape has no meaning
+    /// for, including `-`, which Boolfuck doesn't have.
+    pub fn parse(program: &Program) -> Result<Self, ParseError> {
+        use BitInstruction as B;
+        use Instruction as I;
+
+        let ir = IR::parse(program)?;
+        let mut instructions = Vec::with_capacity(ir.instructions.len());
+        let mut unsupported = Vec::new();
+
+        for instr in &ir.instructions {
+            let lowered = match instr.inner {
+                // runs of `+` are folded modulo 256, which keeps them odd or
+                // even
+                I::Add { amount, .. } => (amount % 2 == 1).then_some(B::Flip),
+                I::ShiftLeft(n) => shift(-((n % BIT_TAPE_LENGTH) as i64)),
+                I::ShiftRight(n) => shift((n % BIT_TAPE_LENGTH) as i64),
+                I::Read { .. } => Some(B::Read),
+                I::Write { .. } => Some(B::Write),
+                I::JumpForward(_) => Some(B::JumpForward(0)),
+                I::JumpBackward(_) => Some(B::JumpBackward(0)),
+                _ => {
+                    let span = instr.span;
+                    let text = &program.source()[span.offset..span.end()];
+                    unsupported.push(Diagnostic {
+                        message: format!(
+                            "`{text}` has no meaning on a bit tape"
+                        ),
+                        snippet: program.snippet(span),
+                        hint: None,
+                    });
+                    None
+                }
+            };
+            instructions.extend(lowered.map(|b| Spanned::new(b, instr.span)));
+        }
+        if !unsupported.is_empty() {
+            return Err(ParseError::Unsupported(unsupported));
+        }
+
+        link(&mut instructions);
+
+        Ok(BitIR { instructions })
+    }
+}
+
+fn shift(by: i64) -> Option<BitInstruction> {
+    (by != 0).then_some(BitInstruction::Shift(by))
+}
+
+/// Points every jump at its partner. The brackets were already matched up
+/// while parsing.
+fn link(instrs: &mut [Spanned<BitInstruction>]) {
+    use BitInstruction as B;
+
+    let mut open = Vec::new();
+    for pc in 0..instrs.len() {
+        match instrs[pc].inner {
+            B::JumpForward(_) => open.push(pc),
+            B::JumpBackward(_) => {
+                let start = open.pop().expect("brackets are balanced");
+                instrs[start].inner = B::JumpForward(pc as u64);
+                instrs[pc].inner = B::JumpBackward(start as u64);
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! A compact binary container for caching a [`Program`] or [`IR`] between
+//! build steps. The payload is prefixed with a header naming the format
+//! version, so a cache written by an incompatible version of the compiler is
+//! rejected rather than misread. Anything deriving the serde traits can also
+//! go through JSON as usual.
+//!
+//! [`Program`]: super::parser::Program
+//! [`IR`]: super::parser::IR
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+const MAGIC: [u8; 4] = *b"CNCS";
+
+/// Bumped whenever the layout of anything that can be serialized changes.
+pub const FORMAT_VERSION: u32 = 7;
+
+const HEADER_LEN: usize = MAGIC.len() + size_of::<u32>();
+
+#[derive(Error, Debug)]
+pub enum ContainerError {
+    #[error("not a serialized program")]
+    BadMagic,
+    #[error("serialized with format version {found}, expected {expected}")]
+    UnsupportedVersion { found: u32, expected: u32 },
+    #[error("could not encode or decode the payload: {0}")]
+    Payload(#[from] bincode::Error),
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, ContainerError> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, value)?;
+
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ContainerError> {
+    if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+        return Err(ContainerError::BadMagic);
+    }
+
+    let version = bytes[MAGIC.len()..HEADER_LEN].try_into().unwrap();
+    let found = u32::from_le_bytes(version);
+    if found != FORMAT_VERSION {
+        return Err(ContainerError::UnsupportedVersion {
+            found,
+            expected: FORMAT_VERSION,
+        });
+    }
+
+    Ok(bincode::deserialize(&bytes[HEADER_LEN..])?)
+}
@@ -0,0 +1,573 @@
+//! A preprocessor for Brainfuck source, which expands macros as it reads the
+//! commands into a [`Program`]:
+//!
+//! ```text
+//! @def move(n) [->*$n+<*$n]
+//! @def print(c) +*$c.[-]
+//! @print(72) @move(2)
+//! ```
+//!
+//! `@def` defines a macro, with the rest of the line as its body, and
+//! `@name(a, b)` expands it, or just `@name` if it has no parameters. In a
+//! body, `$name` stands for the argument given for a parameter. A command,
+//! `$name` or macro call followed straight away by `*` and a count is
+//! repeated that many times, and the count can be a `$name` too. Arguments
+//! are separated by commas, so they can't contain `,` themselves.
+//!
+//! Expansion is hygienic. Arguments are expanded where the macro is called,
+//! so a `$name` in one is the caller's parameter, and a body can only call
+//! macros defined before it. Every command keeps the span it's written at,
+//! along with the macro calls it was expanded from.
+
+use std::{collections::HashMap, ops::Range};
+
+use thiserror::Error;
+
+use super::{
+    parser::{Command, Dialect, Program},
+    span::{Diagnostic, Expansion, Snippet, Span, Spanned},
+};
+
+/// The most commands a program can expand to, and the most macro calls it can
+/// make along the way, so that a large repetition count or deeply nested calls
+/// are an error rather than running out of memory or time.
+pub const EXPANSION_LIMIT: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum MacroError {
+    #[error("{0}")]
+    Syntax(Box<Diagnostic>),
+}
+
+/// A place in the source, which expansion moves forward through a character
+/// at a time so that a span's line and column never have to be counted from
+/// the start of its line.
+#[derive(Clone, Copy, Debug)]
+struct Cursor {
+    offset: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Cursor {
+    const START: Cursor = Cursor {
+        offset: 0,
+        line: 1,
+        col: 1,
+    };
+
+    fn span(self, len: usize, expansion: Option<usize>) -> Span {
+        Span {
+            offset: self.offset,
+            len,
+            line: self.line,
+            col: self.col,
+            expansion,
+        }
+    }
+}
+
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Range<usize>,
+    /// Where the body starts.
+    body_start: Cursor,
+    /// The line it's defined on.
+    line: usize,
+    /// How many macros were defined before it, which are the only ones its
+    /// body can call.
+    visible: usize,
+}
+
+/// What a parameter stands for in one expansion of a macro.
+#[derive(Clone, Debug)]
+struct Argument {
+    commands: Vec<Spanned<Command>>,
+    /// The number the argument is written as, for repetition counts.
+    count: Option<u64>,
+}
+
+/// Where the source being expanded is, either at the top level or in a
+/// macro's body.
+struct Scope {
+    /// The index of the macro being expanded.
+    current: Option<usize>,
+    args: HashMap<String, Argument>,
+    expansion: Option<usize>,
+}
+
+struct Expander<'a> {
+    name: &'a str,
+    source: &'a str,
+    dialect: Dialect,
+    /// The offset each line starts at.
+    line_starts: Vec<usize>,
+    macros: Vec<Macro>,
+    expansions: Vec<Expansion>,
+}
+
+impl Expander<'_> {
+    /// A cursor at `offset`, found by counting from the start of its line,
+    /// which is only worth doing for errors and definitions.
+    fn cursor(&self, offset: usize) -> Cursor {
+        let line = self.line_starts.partition_point(|&s| s <= offset);
+        let line_start = self.line_starts[line - 1];
+
+        Cursor {
+            offset,
+            line,
+            col: self.source[line_start..offset].chars().count() + 1,
+        }
+    }
+
+    /// Moves `cursor` forward to `offset`.
+    fn advance(&self, mut cursor: Cursor, offset: usize) -> Cursor {
+        for c in self.source[cursor.offset..offset].chars() {
+            if c == '\n' {
+                cursor.line += 1;
+                cursor.col = 1;
+            } else {
+                cursor.col += 1;
+            }
+        }
+        cursor.offset = offset;
+
+        cursor
+    }
+
+    fn span(&self, range: Range<usize>, expansion: Option<usize>) -> Span {
+        self.cursor(range.start).span(range.len(), expansion)
+    }
+
+    fn error(&self, span: Span, message: impl Into<String>) -> MacroError {
+        MacroError::Syntax(Box::new(Diagnostic {
+            message: message.into(),
+            snippet: Snippet::expanded(
+                self.name,
+                self.source,
+                span,
+                &self.expansions,
+            ),
+            hint: None,
+        }))
+    }
+
+    /// The name starting at `at`, which is empty if there isn't one.
+    fn ident(&self, at: usize, end: usize) -> &str {
+        let text = &self.source[at..end];
+        let len = text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(text.len());
+
+        match text.starts_with(|c: char| c.is_ascii_digit()) {
+            true => "",
+            false => &text[..len],
+        }
+    }
+
+    /// Expands everything in `region`, which `start` is at the start of,
+    /// adding the commands it comes to onto `out`.
+    fn expand(
+        &mut self,
+        region: Range<usize>,
+        start: Cursor,
+        scope: &Scope,
+        out: &mut Vec<Spanned<Command>>,
+    ) -> Result<(), MacroError> {
+        let mut i = region.start;
+        let mut cursor = start;
+        // where the last command, `$name` or call starts in `out`, if it ends
+        // right where `i` is
+        let mut unit: Option<usize> = None;
+
+        while i < region.end {
+            let start = i;
+            cursor = self.advance(cursor, i);
+            let c = self.source[i..].chars().next().expect("i is in bounds");
+            let mut next_unit = None;
+
+            match c {
+                '@' if self.ident(i + 1, region.end) == "def" => {
+                    if scope.current.is_some() {
+                        let span = self.span(i..i + 4, scope.expansion);
+                        return Err(self.error(
+                            span,
+                            "macros can't be defined inside another macro",
+                        ));
+                    }
+                    i = self.define(i, region.end)?;
+                }
+                '@' if !self.ident(i + 1, region.end).is_empty() => {
+                    next_unit = Some(out.len());
+                    i = self.call(cursor, region.end, scope, out)?;
+                }
+                '$' if scope.current.is_some() => {
+                    let name = self.ident(i + 1, region.end);
+                    let end = i + 1 + name.len();
+                    let arg = self.argument_for(i..end, scope)?;
+                    next_unit = Some(out.len());
+                    out.extend_from_slice(&arg.commands);
+                    i = end;
+                }
+                '*' if unit.is_some() => {
+                    if let Some((count, end)) =
+                        self.count(i + 1, region.end, scope)?
+                    {
+                        self.repeat(out, unit.unwrap_or_default(), count)
+                            .map_err(|message| {
+                                let span =
+                                    cursor.span(end - i, scope.expansion);
+                                self.error(span, message)
+                            })?;
+                        i = end;
+                    } else {
+                        i += 1;
+                    }
+                }
+                _ => {
+                    let command = u8::try_from(c)
+                        .ok()
+                        .and_then(|c| Command::try_from(c).ok())
+                        .filter(|c| self.dialect.contains(c.dialect()));
+                    if let Some(command) = command {
+                        let span = cursor.span(1, scope.expansion);
+                        next_unit = Some(out.len());
+                        out.push(Spanned::new(command, span));
+                    }
+                    i += c.len_utf8();
+                }
+            }
+
+            if out.len() > EXPANSION_LIMIT {
+                let span = self.span(start..i, scope.expansion);
+                return Err(self.error(span, too_long()));
+            }
+            unit = next_unit;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the `@def` at `at`, returning where the line it's on ends.
+    fn define(&mut self, at: usize, end: usize) -> Result<usize, MacroError> {
+        let line_end = self.source[at..end].find('\n').map_or(end, |n| at + n);
+        let line = &self.source[..line_end];
+
+        let mut i = at + "@def".len();
+        i += line[i..].len() - line[i..].trim_start().len();
+        let name = self.ident(i, line_end).to_owned();
+        if name.is_empty() {
+            let span = self.span(at..i, None);
+            return Err(self.error(span, "`@def` needs a name for the macro"));
+        }
+        let name_span = self.span(i..i + name.len(), None);
+        if let Some(earlier) = self.macros.iter().find(|m| m.name == name) {
+            let message = format!(
+                "`@{name}` is already defined on line {}",
+                earlier.line
+            );
+            return Err(self.error(name_span, message));
+        }
+        i += name.len();
+
+        let mut params: Vec<String> = Vec::new();
+        if line[i..].starts_with('(') {
+            let Some(close) = line[i..].find(')').map(|n| i + n) else {
+                let span = self.span(i..i + 1, None);
+                return Err(self.error(span, "the parameter list never ends"));
+            };
+            let list = &line[i + 1..close];
+
+            if !list.trim().is_empty() {
+                let mut offset = i + 1;
+                for param in list.split(',') {
+                    let trimmed = param.trim();
+                    let start = offset + param.find(trimmed).unwrap_or(0);
+                    let span = self.span(start..start + trimmed.len(), None);
+                    offset += param.len() + 1;
+
+                    if trimmed.is_empty() || self.ident(start, close) != trimmed
+                    {
+                        let message =
+                            format!("`{trimmed}` isn't a parameter name");
+                        return Err(self.error(span, message));
+                    }
+                    if params.iter().any(|p| p == trimmed) {
+                        let message =
+                            format!("`{trimmed}` is already a parameter");
+                        return Err(self.error(span, message));
+                    }
+                    params.push(trimmed.to_owned());
+                }
+            }
+            i = close + 1;
+        }
+
+        self.macros.push(Macro {
+            name,
+            params,
+            body: i..line_end,
+            body_start: self.cursor(i),
+            line: name_span.line,
+            visible: self.macros.len(),
+        });
+
+        Ok(line_end)
+    }
+
+    /// Expands the macro called at `cursor`, returning where the call ends.
+    fn call(
+        &mut self,
+        cursor: Cursor,
+        end: usize,
+        scope: &Scope,
+        out: &mut Vec<Spanned<Command>>,
+    ) -> Result<usize, MacroError> {
+        let at = cursor.offset;
+        let name = self.ident(at + 1, end).to_owned();
+        let mut i = at + 1 + name.len();
+
+        let visible = scope
+            .current
+            .map_or(self.macros.len(), |m| self.macros[m].visible);
+        let index = self.macros.iter().position(|m| m.name == name);
+        let index = match (index, scope.current) {
+            (Some(index), _) if index < visible => index,
+            (Some(index), Some(current)) => {
+                let span = self.span(at..i, scope.expansion);
+                let message = match index == current {
+                    true => format!("`@{name}` can't call itself"),
+                    false => format!(
+                        "`@{name}` is defined after `@{}`, on line {}",
+                        self.macros[current].name, self.macros[index].line
+                    ),
+                };
+                return Err(self.error(span, message));
+            }
+            _ => {
+                let span = self.span(at..i, scope.expansion);
+                let message = format!("there's no macro called `@{name}`");
+                return Err(self.error(span, message));
+            }
+        };
+
+        let mut args = Vec::new();
+        if self.source[i..end].starts_with('(') {
+            let open = i;
+            let mut depth = 0;
+            let mut arg_start = i + 1;
+            let mut close = None;
+            for (n, c) in self.source[i + 1..end].char_indices() {
+                let pos = i + 1 + n;
+                match c {
+                    '(' => depth += 1,
+                    ')' if depth == 0 => {
+                        args.push(arg_start..pos);
+                        close = Some(pos);
+                        break;
+                    }
+                    ')' => depth -= 1,
+                    ',' if depth == 0 => {
+                        args.push(arg_start..pos);
+                        arg_start = pos + 1;
+                    }
+                    _ => (),
+                }
+            }
+            let Some(close) = close else {
+                let span = self.span(open..open + 1, scope.expansion);
+                return Err(self.error(span, "the argument list never ends"));
+            };
+            if let [only] = &args[..] {
+                if self.source[only.clone()].trim().is_empty() {
+                    args.clear();
+                }
+            }
+            i = close + 1;
+        }
+
+        let call = cursor.span(i - at, scope.expansion);
+        let params = self.macros[index].params.clone();
+        if args.len() != params.len() {
+            let message = format!(
+                "`@{name}` takes {} argument{}, but {} {} given",
+                params.len(),
+                if params.len() == 1 { "" } else { "s" },
+                args.len(),
+                if args.len() == 1 { "was" } else { "were" },
+            );
+            return Err(self.error(call, message));
+        }
+
+        // calls that come to nothing still take time, so they count too
+        if self.expansions.len() >= EXPANSION_LIMIT {
+            let message =
+                format!("this makes more than {EXPANSION_LIMIT} macro calls");
+            return Err(self.error(call, message));
+        }
+
+        // arguments are expanded where the macro is called, before any of the
+        // body is
+        let mut values = HashMap::new();
+        let mut arg_start = cursor;
+        for (param, range) in params.into_iter().zip(args) {
+            arg_start = self.advance(arg_start, range.start);
+            values.insert(param, self.argument(range, arg_start, scope)?);
+        }
+
+        self.expansions.push(Expansion { name, call });
+        let inner = Scope {
+            current: Some(index),
+            args: values,
+            expansion: Some(self.expansions.len() - 1),
+        };
+        let called = &self.macros[index];
+        self.expand(called.body.clone(), called.body_start, &inner, out)?;
+
+        Ok(i)
+    }
+
+    fn argument(
+        &mut self,
+        range: Range<usize>,
+        start: Cursor,
+        scope: &Scope,
+    ) -> Result<Argument, MacroError> {
+        let text = self.source[range.clone()].trim();
+
+        // passing a parameter on passes on its count too
+        if let Some(arg) =
+            text.strip_prefix('$').and_then(|name| scope.args.get(name))
+        {
+            return Ok(arg.clone());
+        }
+
+        let mut commands = Vec::new();
+        self.expand(range, start, scope, &mut commands)?;
+
+        Ok(Argument {
+            commands,
+            count: text.parse().ok(),
+        })
+    }
+
+    /// The argument `$name` at `range` stands for.
+    fn argument_for<'s>(
+        &self,
+        range: Range<usize>,
+        scope: &'s Scope,
+    ) -> Result<&'s Argument, MacroError> {
+        let name = &self.source[range.start + 1..range.end];
+        scope.args.get(name).ok_or_else(|| {
+            let current = scope.current.map(|m| &self.macros[m].name);
+            let message = format!(
+                "`${name}` isn't a parameter of `@{}`",
+                current.map_or("", String::as_str)
+            );
+            self.error(self.span(range, scope.expansion), message)
+        })
+    }
+
+    /// The repetition count starting at `at`, and where it ends, if there's
+    /// one there.
+    fn count(
+        &self,
+        at: usize,
+        end: usize,
+        scope: &Scope,
+    ) -> Result<Option<(u64, usize)>, MacroError> {
+        let text = &self.source[at..end];
+
+        if text.starts_with(|c: char| c.is_ascii_digit()) {
+            let len = text
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(text.len());
+            return match text[..len].parse() {
+                Ok(count) => Ok(Some((count, at + len))),
+                Err(_) => {
+                    let span = self.span(at..at + len, scope.expansion);
+                    Err(self.error(span, too_long()))
+                }
+            };
+        }
+
+        if text.starts_with('$') && scope.current.is_some() {
+            let name = self.ident(at + 1, end);
+            let range = at..at + 1 + name.len();
+            let arg = self.argument_for(range.clone(), scope)?;
+            let Some(count) = arg.count else {
+                let span = self.span(range, scope.expansion);
+                let message =
+                    format!("`${name}` has to be a number to repeat by it");
+                return Err(self.error(span, message));
+            };
+            return Ok(Some((count, at + 1 + name.len())));
+        }
+
+        Ok(None)
+    }
+
+    /// Repeats everything in `out` from `start` on so it's there `count`
+    /// times.
+    fn repeat(
+        &self,
+        out: &mut Vec<Spanned<Command>>,
+        start: usize,
+        count: u64,
+    ) -> Result<(), String> {
+        let unit = out.split_off(start);
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(unit.len()))
+            .filter(|&len| start + len <= EXPANSION_LIMIT)
+            .ok_or_else(too_long)?;
+
+        out.extend(unit.iter().copied().cycle().take(len));
+
+        Ok(())
+    }
+}
+
+fn too_long() -> String {
+    format!("this expands to more than {EXPANSION_LIMIT} commands")
+}
+
+/// Reads Brainfuck source, expanding any macros in it. Embedded input, when
+/// the dialect allows for it, is left as it is.
+pub fn expand(
+    name: &str,
+    source: &str,
+    dialect: Dialect,
+) -> Result<Program, MacroError> {
+    let (code, input) = match source.split_once('!') {
+        Some((code, input)) if dialect.contains(Dialect::EMBEDDED_INPUT) => {
+            (code, input.as_bytes())
+        }
+        _ => (source, &[][..]),
+    };
+
+    let line_starts = [0]
+        .into_iter()
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let mut expander = Expander {
+        name,
+        source,
+        dialect,
+        line_starts,
+        macros: vec![],
+        expansions: vec![],
+    };
+    let top_level = Scope {
+        current: None,
+        args: HashMap::new(),
+        expansion: None,
+    };
+    let mut commands = Vec::new();
+    expander.expand(0..code.len(), Cursor::START, &top_level, &mut commands)?;
+
+    Ok(Program::from_commands(name, source, dialect, commands)
+        .with_input(input)
+        .with_expansions(expander.expansions))
+}
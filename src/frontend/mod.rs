@@ -1 +1,9 @@
+pub mod bits;
+#[cfg(feature = "serde")]
+pub mod container;
+pub mod macros;
+pub mod optimizer;
 pub mod parser;
+pub mod span;
+pub mod text;
+pub mod tokens;
@@ -0,0 +1,512 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    parser::{relink, CellWidth, Instruction, IR, TAPE_LENGTH},
+    span::{Span, Spanned},
+};
+
+/// Runs every optimization pass over the program, along with what
+/// [`remove_dead_loops`] took out of it.
+pub fn optimize(ir: IR) -> (IR, DeadLoops) {
+    let (ir, dead) = remove_dead_loops(ir);
+    let ir = fold_scan_loops(fold_clear_loops(fold_mul_loops(ir)));
+
+    (normalize(defer_movement(ir)), dead)
+}
+
+/// Net change an `Add` or `Sub` makes to the cell at its offset.
+fn net_change(instr: &Instruction) -> Option<(i64, i128)> {
+    match *instr {
+        Instruction::Add { amount, offset } => Some((offset, amount as i128)),
+        Instruction::Sub { amount, offset } => {
+            Some((offset, -(amount as i128)))
+        }
+        _ => None,
+    }
+}
+
+/// Net distance a shift moves the pointer.
+fn net_shift(instr: &Instruction) -> Option<i64> {
+    match *instr {
+        Instruction::ShiftLeft(n) => Some(-(n as i64)),
+        Instruction::ShiftRight(n) => Some(n as i64),
+        _ => None,
+    }
+}
+
+/// Combines two neighbouring instructions into their net effect. `None` if
+/// they can't be combined, `Some(None)` if they cancel each other out.
+fn combine(
+    prev: &Instruction,
+    next: &Instruction,
+    width: CellWidth,
+) -> Option<Option<Instruction>> {
+    use Instruction as I;
+
+    if let (Some((offset, a)), Some((o, b))) =
+        (net_change(prev), net_change(next))
+    {
+        if offset != o {
+            return None;
+        }
+
+        let net = a + b;
+        let amount = width.wrap(net.unsigned_abs() as u64);
+        return Some(match net.signum() {
+            _ if amount == 0 => None,
+            1 => Some(I::Add { amount, offset }),
+            _ => Some(I::Sub { amount, offset }),
+        });
+    }
+
+    let net = net_shift(prev)? + net_shift(next)?;
+    Some(match net.signum() {
+        1 => Some(I::ShiftRight(net as u64)),
+        -1 => Some(I::ShiftLeft(net.unsigned_abs())),
+        _ => None,
+    })
+}
+
+/// Merges runs of arithmetic on the same cell into a single `Add` or `Sub`,
+/// wrapping at the cell width, and runs of shifts into a single shift. Runs
+/// that cancel out disappear. Jump targets are left for the caller to fix up.
+pub(crate) fn fold_runs(
+    instrs: &[Spanned<Instruction>],
+    width: CellWidth,
+) -> Vec<Spanned<Instruction>> {
+    let mut out: Vec<Spanned<Instruction>> = Vec::with_capacity(instrs.len());
+
+    for &instr in instrs {
+        match out
+            .last()
+            .and_then(|prev| combine(&prev.inner, &instr.inner, width))
+        {
+            Some(Some(inner)) => {
+                let prev = out.last_mut().unwrap();
+                *prev = Spanned::new(inner, prev.span.to(instr.span));
+            }
+            // the run cancelled out, so whatever came before it may now
+            // merge with what comes after
+            Some(None) => {
+                out.pop();
+            }
+            None => out.push(instr),
+        }
+    }
+
+    out
+}
+
+/// Folds runs of mixed `+`/`-` and `<`/`>` into their net effect. See
+/// [`fold_runs`].
+pub fn normalize(ir: IR) -> IR {
+    let mut out = fold_runs(&ir.instructions, ir.cell_width);
+
+    relink(&mut out);
+
+    IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    }
+}
+
+/// Replaces loops like `[->+>+++<<]`, which move back to where they started
+/// and decrement the current cell by one each time around, with a `MulAdd`
+/// for each other cell they touch, followed by clearing the current cell.
+pub fn fold_mul_loops(ir: IR) -> IR {
+    use Instruction as I;
+
+    let width = ir.cell_width;
+    let mut out = Vec::with_capacity(ir.instructions.len());
+
+    let mut pc = 0;
+    while pc < ir.instructions.len() {
+        let instr = ir.instructions[pc];
+
+        let I::JumpForward(end) = instr.inner else {
+            out.push(instr);
+            pc += 1;
+            continue;
+        };
+        let end = end as usize;
+
+        // net change to every cell the loop touches, by offset
+        let mut deltas = BTreeMap::new();
+        let mut offset = 0i64;
+        let simple = ir.instructions[pc + 1..end].iter().all(|i| {
+            match i.inner {
+                I::ShiftLeft(n) => offset -= n as i64,
+                I::ShiftRight(n) => offset += n as i64,
+                I::Add { amount, offset: o } => {
+                    let d = deltas.entry(offset + o).or_insert(0u64);
+                    *d = width.wrap(d.wrapping_add(amount));
+                }
+                I::Sub { amount, offset: o } => {
+                    let d = deltas.entry(offset + o).or_insert(0u64);
+                    *d = width.wrap(d.wrapping_sub(amount));
+                }
+                _ => return false,
+            }
+            true
+        });
+
+        if !simple || offset != 0 || deltas.remove(&0) != Some(width.max()) {
+            out.push(instr);
+            pc += 1;
+            continue;
+        }
+
+        let span = instr.span.to(ir.instructions[end].span);
+        out.extend(deltas.into_iter().filter(|&(_, factor)| factor != 0).map(
+            |(offset, factor)| Spanned::new(I::MulAdd { offset, factor }, span),
+        ));
+        out.push(Spanned::new(
+            I::Set {
+                value: 0,
+                offset: 0,
+            },
+            span,
+        ));
+        pc = end + 1;
+    }
+
+    relink(&mut out);
+
+    IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    }
+}
+
+/// Folds an `Add` or `Sub` into the `Set` of the same cell right before it.
+fn fold_into_set(
+    set: &mut Spanned<Instruction>,
+    next: &Spanned<Instruction>,
+    width: CellWidth,
+) -> bool {
+    use Instruction as I;
+
+    let I::Set { value, offset } = &mut set.inner else {
+        return false;
+    };
+
+    match next.inner {
+        I::Add { amount, offset: o } if o == *offset => {
+            *value = width.wrap(value.wrapping_add(amount))
+        }
+        I::Sub { amount, offset: o } if o == *offset => {
+            *value = width.wrap(value.wrapping_sub(amount))
+        }
+        _ => return false,
+    }
+
+    set.span = set.span.to(next.span);
+    true
+}
+
+/// Replaces loops that only count the current cell down to zero, like `[-]`
+/// and `[+]`, with a `Set`. Arithmetic on the cell directly around a `Set` is
+/// folded into it as well, so `+[-]+++` becomes `Set(3)`.
+pub fn fold_clear_loops(ir: IR) -> IR {
+    use Instruction as I;
+
+    let mut out: Vec<Spanned<Instruction>> =
+        Vec::with_capacity(ir.instructions.len());
+
+    let mut instrs = &ir.instructions[..];
+    while let Some(first) = instrs.first() {
+        let (mut next, len) = match instrs {
+            [open, step, close, ..]
+                if matches!(open.inner, I::JumpForward(_))
+                    && matches!(close.inner, I::JumpBackward(_))
+                    // an odd step always passes through zero
+                    && matches!(
+                        step.inner,
+                        I::Add { amount, offset: 0 }
+                            | I::Sub { amount, offset: 0 } if amount % 2 == 1
+                    ) =>
+            {
+                let set = I::Set {
+                    value: 0,
+                    offset: 0,
+                };
+                (Spanned::new(set, open.span.to(close.span)), 3)
+            }
+            _ => (*first, 1),
+        };
+        instrs = &instrs[len..];
+
+        if let I::Set { offset, .. } = next.inner {
+            // whatever was added to the cell right before is overwritten
+            while let Some(prev) = out.last() {
+                if !matches!(
+                    prev.inner,
+                    I::Add { offset: o, .. }
+                        | I::Sub { offset: o, .. }
+                        | I::Set { offset: o, .. } if o == offset
+                ) {
+                    break;
+                }
+                next.span = prev.span.to(next.span);
+                out.pop();
+            }
+        } else if out
+            .last_mut()
+            .is_some_and(|set| fold_into_set(set, &next, ir.cell_width))
+        {
+            continue;
+        }
+
+        out.push(next);
+    }
+
+    relink(&mut out);
+
+    IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    }
+}
+
+/// Replaces loops that only move the pointer, like `[>]` or `[<<<]`, with a
+/// `Scan`.
+pub fn fold_scan_loops(ir: IR) -> IR {
+    use Instruction as I;
+
+    let mut out: Vec<Spanned<Instruction>> =
+        Vec::with_capacity(ir.instructions.len());
+
+    let mut instrs = &ir.instructions[..];
+    while let Some((first, rest)) = instrs.split_first() {
+        let stride = match instrs {
+            [open, shift, close, ..]
+                if matches!(open.inner, I::JumpForward(_))
+                    && matches!(close.inner, I::JumpBackward(_)) =>
+            {
+                match shift.inner {
+                    I::ShiftLeft(n) => Some(-(n as i64)),
+                    I::ShiftRight(n) => Some(n as i64),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        match stride {
+            Some(stride) => {
+                let span = first.span.to(instrs[2].span);
+                out.push(Spanned::new(I::Scan { stride }, span));
+                instrs = &instrs[3..];
+            }
+            None => {
+                out.push(*first);
+                instrs = rest;
+            }
+        }
+    }
+
+    relink(&mut out);
+
+    IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    }
+}
+
+/// Stops moving the pointer inside straight-line code. Instructions address
+/// cells relative to where the pointer would have been instead, and the
+/// pointer catches up in one move right before the next loop, `MulAdd` or
+/// `Scan`.
+pub fn defer_movement(ir: IR) -> IR {
+    use Instruction as I;
+
+    fn flush(
+        out: &mut Vec<Spanned<Instruction>>,
+        pending: &mut Option<(i64, Span)>,
+    ) {
+        match pending.take() {
+            Some((by, span)) if by > 0 => {
+                out.push(Spanned::new(I::ShiftRight(by as u64), span))
+            }
+            Some((by, span)) if by < 0 => {
+                out.push(Spanned::new(I::ShiftLeft(by.unsigned_abs()), span))
+            }
+            _ => (),
+        }
+    }
+
+    let mut out = Vec::with_capacity(ir.instructions.len());
+    let mut pending: Option<(i64, Span)> = None;
+
+    for instr in &ir.instructions {
+        let by = match instr.inner {
+            I::ShiftLeft(n) => -(n as i64),
+            I::ShiftRight(n) => n as i64,
+            _ => {
+                let mut instr = *instr;
+                match (instr.inner.offset_mut(), pending) {
+                    (Some(offset), Some((by, _))) => *offset += by,
+                    (Some(_), None) => (),
+                    (None, _) => flush(&mut out, &mut pending),
+                }
+                out.push(instr);
+                continue;
+            }
+        };
+
+        pending = Some(match pending {
+            Some((pending, span)) => (pending + by, span.to(instr.span)),
+            None => (by, instr.span),
+        });
+    }
+    flush(&mut out, &mut pending);
+
+    relink(&mut out);
+
+    IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    }
+}
+
+/// What [`remove_dead_loops`] took out of the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeadLoops {
+    /// Loops removed, counting every loop nested inside a dead one.
+    pub loops: usize,
+    /// Instructions removed, including the jumps.
+    pub instructions: usize,
+}
+
+/// Cells known to be zero, by offset from the pointer wrapped onto the tape.
+#[derive(Clone, Debug)]
+enum KnownZero {
+    /// Every cell is zero except maybe these.
+    AllBut(BTreeSet<i64>),
+    /// Only these cells are zero.
+    Only(BTreeSet<i64>),
+}
+
+impl KnownZero {
+    fn wrap(offset: i64) -> i64 {
+        offset.rem_euclid(TAPE_LENGTH as i64)
+    }
+
+    fn only_current() -> Self {
+        KnownZero::Only(BTreeSet::from([0]))
+    }
+
+    fn is_zero(&self, offset: i64) -> bool {
+        match self {
+            KnownZero::AllBut(cells) => !cells.contains(&Self::wrap(offset)),
+            KnownZero::Only(cells) => cells.contains(&Self::wrap(offset)),
+        }
+    }
+
+    fn set(&mut self, offset: i64, zero: bool) {
+        let offset = Self::wrap(offset);
+        match (self, zero) {
+            (KnownZero::AllBut(cells), true) => cells.remove(&offset),
+            (KnownZero::AllBut(cells), false) => cells.insert(offset),
+            (KnownZero::Only(cells), true) => cells.insert(offset),
+            (KnownZero::Only(cells), false) => cells.remove(&offset),
+        };
+    }
+
+    fn shift(&mut self, by: i64) {
+        let (KnownZero::AllBut(cells) | KnownZero::Only(cells)) = self;
+        *cells = cells.iter().map(|&o| Self::wrap(o - by)).collect();
+    }
+}
+
+/// Removes loops that can never run because the current cell is known to be
+/// zero when they're reached, like a loop at the very start of the program
+/// or one right after another loop ends.
+pub fn remove_dead_loops(ir: IR) -> (IR, DeadLoops) {
+    use Instruction as I;
+
+    let mut out = Vec::with_capacity(ir.instructions.len());
+    let mut removed = DeadLoops::default();
+
+    // the tape starts out zeroed
+    let mut zero = KnownZero::AllBut(BTreeSet::new());
+    // what was known before each procedure being defined, since defining one
+    // skips its body
+    let mut defining = Vec::new();
+
+    let mut pc = 0;
+    while pc < ir.instructions.len() {
+        let instr = ir.instructions[pc];
+
+        match instr.inner {
+            I::JumpForward(end) if zero.is_zero(0) => {
+                let dead = &ir.instructions[pc..=end as usize];
+                removed.loops += dead
+                    .iter()
+                    .filter(|i| matches!(i.inner, I::JumpForward(_)))
+                    .count();
+                removed.instructions += dead.len();
+                pc = end as usize + 1;
+                continue;
+            }
+            // a scan over a zero cell doesn't move
+            I::Scan { .. } if zero.is_zero(0) => {
+                removed.loops += 1;
+                removed.instructions += 1;
+                pc += 1;
+                continue;
+            }
+            I::ShiftLeft(n) => zero.shift(-(n as i64)),
+            I::ShiftRight(n) => zero.shift(n as i64),
+            I::Add { amount, offset } | I::Sub { amount, offset } => {
+                if amount != 0 {
+                    zero.set(offset, false)
+                }
+            }
+            I::Set { value, offset } => zero.set(offset, value == 0),
+            I::MulAdd { offset, .. } => {
+                if !zero.is_zero(0) {
+                    zero.set(offset, false)
+                }
+            }
+            I::Read { offset } => zero.set(offset, false),
+            I::Write { .. } | I::Debug => (),
+            // nothing is known about the body, it may be reached from its end
+            I::JumpForward(_) => zero = KnownZero::Only(BTreeSet::new()),
+            // loops and scans only stop on a zero cell
+            I::JumpBackward(_) | I::Scan { .. } => {
+                zero = KnownZero::only_current()
+            }
+            // a procedure can be called from anywhere, with any tape
+            I::DefineProc(_) => {
+                defining.push(zero);
+                zero = KnownZero::Only(BTreeSet::new());
+            }
+            I::EndProc(_) => {
+                zero = defining.pop().expect("procedures are balanced")
+            }
+            I::CallProc => zero = KnownZero::Only(BTreeSet::new()),
+            // the parent and child carry on from different cells
+            I::Fork => zero = KnownZero::Only(BTreeSet::new()),
+            // the kernel can write into any cell
+            I::Syscall => zero = KnownZero::Only(BTreeSet::new()),
+        }
+
+        out.push(instr);
+        pc += 1;
+    }
+
+    relink(&mut out);
+
+    let ir = IR {
+        instructions: out,
+        cell_width: ir.cell_width,
+        input: ir.input,
+    };
+    (ir, removed)
+}
@@ -1,11 +1,19 @@
+use bitflags::bitflags;
 use derive_more::TryFrom;
 use itertools::Itertools;
 use thiserror::Error;
 
-#[derive(Clone, Copy, Debug, TryFrom)]
+use super::{
+    optimizer::fold_runs,
+    span::{Diagnostic, Expansion, Snippet, Span, Spanned},
+};
+
+/// A single Brainfuck command, as the character it's written with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, TryFrom)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[try_from(repr)]
 #[repr(u8)]
-enum Command {
+pub enum Command {
     Movr = b'>',
     Movl = b'<',
     Incr = b'+',
@@ -14,107 +22,640 @@ enum Command {
     Read = b',',
     JmpF = b'[',
     JmpB = b']',
+    Dump = b'#',
+    DefP = b'(',
+    EndP = b')',
+    Call = b':',
+    Fork = b'Y',
+    Sys = b'%',
+}
+
+impl Command {
+    pub const ALL: [Command; 14] = [
+        Command::Movr,
+        Command::Movl,
+        Command::Incr,
+        Command::Decr,
+        Command::Writ,
+        Command::Read,
+        Command::JmpF,
+        Command::JmpB,
+        Command::Dump,
+        Command::DefP,
+        Command::EndP,
+        Command::Call,
+        Command::Fork,
+        Command::Sys,
+    ];
+
+    /// The character the command is written with in Brainfuck.
+    pub fn char(self) -> char {
+        self as u8 as char
+    }
+
+    /// The dialect a program has to opt in to for this to be a command rather
+    /// than a comment.
+    pub fn dialect(self) -> Dialect {
+        match self {
+            Command::Dump => Dialect::DEBUG_DUMP,
+            Command::DefP | Command::EndP | Command::Call => {
+                Dialect::PROCEDURES
+            }
+            Command::Fork => Dialect::FORK,
+            Command::Sys => Dialect::SYSCALLS,
+            _ => Dialect::empty(),
+        }
+    }
 }
 
+bitflags! {
+    /// Extensions to plain Brainfuck a program can opt in to. Without them,
+    /// the characters they use are comments like any other.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    #[cfg_attr(
+        feature = "serde",
+        derive(serde::Serialize, serde::Deserialize)
+    )]
+    pub struct Dialect: u32 {
+        /// `#` writes the pointer and the cells around it to stderr.
+        const DEBUG_DUMP = 1 << 0;
+        /// The program ends at the first `!`, and everything after it is
+        /// input, read before anything on stdin.
+        const EMBEDDED_INPUT = 1 << 1;
+        /// pbrain's procedures: `(` starts defining the procedure numbered by
+        /// the current cell and `)` ends it, and `:` calls the procedure
+        /// numbered by the current cell.
+        const PROCEDURES = 1 << 2;
+        /// Brainfork's `Y` forks the process. The parent's current cell is
+        /// zeroed, and the child moves one cell right and sets it to 1.
+        const FORK = 1 << 3;
+        /// Systemf's `%` makes a Linux syscall described by the cells from
+        /// the current one onwards. See [`Instruction::Syscall`].
+        const SYSCALLS = 1 << 4;
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Program {
+    name: String,
+    source: String,
+    dialect: Dialect,
     instrs: Vec<Command>,
+    spans: Vec<Span>,
+    input: Vec<u8>,
+    /// Every macro call the commands were expanded from.
+    expansions: Vec<Expansion>,
+}
+
+impl Program {
+    pub fn new(name: &str, source: &str) -> Self {
+        Self::with_dialect(name, source, Dialect::empty())
+    }
+
+    pub fn with_dialect(name: &str, source: &str, dialect: Dialect) -> Self {
+        let mut instrs = Vec::new();
+        let mut spans = Vec::new();
+
+        let (code, input) = match source.split_once('!') {
+            Some((code, input))
+                if dialect.contains(Dialect::EMBEDDED_INPUT) =>
+            {
+                (code, input.as_bytes())
+            }
+            _ => (source, &[][..]),
+        };
+
+        let (mut line, mut col) = (1, 1);
+        for (offset, c) in code.bytes().enumerate() {
+            let command = Command::try_from(c).ok();
+            if let Some(command) =
+                command.filter(|c| dialect.contains(c.dialect()))
+            {
+                instrs.push(command);
+                spans.push(Span {
+                    offset,
+                    len: 1,
+                    line,
+                    col,
+                    expansion: None,
+                });
+            }
+
+            if c == b'\n' {
+                line += 1;
+                col = 1;
+            } else if c & 0xC0 != 0x80 {
+                // don't count UTF-8 continuation bytes as their own column
+                col += 1;
+            }
+        }
+
+        Program {
+            name: name.to_owned(),
+            source: source.to_owned(),
+            dialect,
+            instrs,
+            spans,
+            input: input.to_vec(),
+            expansions: vec![],
+        }
+    }
+
+    /// A program whose commands were found some other way than by reading
+    /// Brainfuck, each spanning wherever it was found in `source`.
+    pub(crate) fn from_commands(
+        name: &str,
+        source: &str,
+        dialect: Dialect,
+        commands: Vec<Spanned<Command>>,
+    ) -> Self {
+        let (instrs, spans) =
+            commands.into_iter().map(|c| (c.inner, c.span)).unzip();
+
+        Program {
+            name: name.to_owned(),
+            source: source.to_owned(),
+            dialect,
+            instrs,
+            spans,
+            input: vec![],
+            expansions: vec![],
+        }
+    }
+
+    pub(crate) fn with_input(mut self, input: &[u8]) -> Self {
+        self.input = input.to_vec();
+        self
+    }
+
+    /// Records the macro calls the commands' spans point to.
+    pub(crate) fn with_expansions(
+        mut self,
+        expansions: Vec<Expansion>,
+    ) -> Self {
+        self.expansions = expansions;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.instrs
+    }
+
+    /// Everything after the `!`, when the dialect allows for it.
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+
+    /// Every macro call the program's commands came out of, which their
+    /// spans refer to.
+    pub fn expansions(&self) -> &[Expansion] {
+        &self.expansions
+    }
+
+    pub fn snippet(&self, span: Span) -> Snippet {
+        Snippet::expanded(&self.name, &self.source, span, &self.expansions)
+    }
+
+    /// Byte offset of a 1-based line and column, counted the same way as in
+    /// a [`Span`]. The column just past the end of a line is allowed.
+    pub fn offset(&self, line: usize, col: usize) -> Option<usize> {
+        let start = match line {
+            0 => return None,
+            1 => 0,
+            _ => {
+                let (newline, _) =
+                    self.source.match_indices('\n').nth(line - 2)?;
+                newline + 1
+            }
+        };
+        let text = self.source[start..].split('\n').next()?;
+
+        text.char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .nth(col.checked_sub(1)?)
+            .map(|i| start + i)
+    }
+
+    fn lines(&self) -> impl Iterator<Item = &str> {
+        self.source.lines()
+    }
+}
+
+/// Width of the leading whitespace of a line, or `None` if it is blank.
+fn indentation(line: &str) -> Option<usize> {
+    let trimmed = line.trim_start();
+    (!trimmed.is_empty()).then(|| line.len() - trimmed.len())
 }
 
 impl From<&str> for Program {
     fn from(value: &str) -> Self {
-        let instrs = value.bytes().flat_map(|c| c.try_into()).collect();
+        Program::new("<source>", value)
+    }
+}
+
+/// Number of cells on the tape. Moving past either end wraps around to the
+/// other.
+pub const TAPE_LENGTH: u32 = 30_000;
+
+/// How many procedures a program can define, numbered from 0. Defining one
+/// with a larger number is an error at run time.
+pub const PROCEDURE_COUNT: u64 = 256;
 
-        Program { instrs }
+/// How many procedure calls can be in progress at once.
+pub const CALL_DEPTH_LIMIT: usize = 1 << 16;
+
+/// The most arguments a Linux syscall takes.
+pub const SYSCALL_ARGUMENTS: usize = 6;
+
+/// How many bits each cell on the tape holds. Arithmetic on cells wraps at
+/// this width.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellWidth {
+    pub fn bytes(self) -> u32 {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+            CellWidth::U64 => 8,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() * 8
+    }
+
+    /// The largest value a cell can hold, which is also -1.
+    pub fn max(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+
+    /// Truncates a value to what a cell can hold.
+    pub fn wrap(self, value: u64) -> u64 {
+        value & self.max()
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// A single operation on the tape. Operations with an `offset` work on the
+/// cell that far from the pointer, rather than the current cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     ShiftLeft(u64),
     ShiftRight(u64),
-    Add(u8),
-    Sub(u8),
-    Read,
-    Write,
+    Add {
+        amount: u64,
+        offset: i64,
+    },
+    Sub {
+        amount: u64,
+        offset: i64,
+    },
+    Set {
+        value: u64,
+        offset: i64,
+    },
+    /// Adds the current cell times `factor` to the cell `offset` away.
+    MulAdd {
+        offset: i64,
+        factor: u64,
+    },
+    /// Moves by `stride` cells until the current cell is zero.
+    Scan {
+        stride: i64,
+    },
+    Read {
+        offset: i64,
+    },
+    Write {
+        offset: i64,
+    },
     JumpForward(u64),
     JumpBackward(u64),
+    /// Writes the pointer and the cells around it to stderr.
+    Debug,
+    /// Defines the procedure numbered by the current cell as everything up to
+    /// the matching `EndProc`, then skips past it.
+    DefineProc(u64),
+    /// Returns from the procedure started by the matching `DefineProc`.
+    EndProc(u64),
+    /// Calls the procedure numbered by the current cell.
+    CallProc,
+    /// Forks the process. The parent zeroes the current cell, while the
+    /// child moves one cell right and sets that cell to 1.
+    Fork,
+    /// Makes the Linux syscall laid out on the tape from the current cell:
+    /// its number, how many arguments it takes (at most 6), then for each
+    /// argument a kind, a length and that many cells of data. A kind 0
+    /// argument is the number its cells spell out, most significant cell
+    /// first. Kind 1 passes the address of its cells, as they lie in memory,
+    /// and kind 2 the address of the cell whose index its cells spell out.
+    /// The result, or `-errno`, goes in the current cell.
+    Syscall,
+}
+
+impl Instruction {
+    /// The offset of the cell this instruction works on, for the instructions
+    /// that can work away from the pointer.
+    pub fn offset(&self) -> Option<i64> {
+        use Instruction as I;
+        match *self {
+            I::Add { offset, .. }
+            | I::Sub { offset, .. }
+            | I::Set { offset, .. }
+            | I::Read { offset }
+            | I::Write { offset } => Some(offset),
+            _ => None,
+        }
+    }
+
+    pub fn offset_mut(&mut self) -> Option<&mut i64> {
+        use Instruction as I;
+        match self {
+            I::Add { offset, .. }
+            | I::Sub { offset, .. }
+            | I::Set { offset, .. }
+            | I::Read { offset }
+            | I::Write { offset } => Some(offset),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("Missing matching brace for {0} at position: {1}")]
-    NestingError(char, usize),
+    #[error("{}", .0.iter().join("\n\n"))]
+    NestingErrors(Vec<Diagnostic>),
+    /// Commands the language being parsed has no use for.
+    #[error("{}", .0.iter().join("\n\n"))]
+    Unsupported(Vec<Diagnostic>),
 }
 
-pub struct IR(pub Vec<Instruction>);
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IR {
+    pub instructions: Vec<Spanned<Instruction>>,
+    pub cell_width: CellWidth,
+    /// Input embedded in the program, which `,` reads before stdin.
+    pub input: Vec<u8>,
+}
 
-fn compute_jumps(instrs: &mut [Instruction]) -> Result<(), ParseError> {
-    use Instruction as I;
-    fn find_bracket_offset(
-        mut subprogram: impl Iterator<Item = Instruction>,
-    ) -> Option<usize> {
-        let mut bracket_nesting: i32 = 0;
-        subprogram.position(|elem| {
-            match elem {
-                I::JumpForward(_) => bracket_nesting += 1,
-                I::JumpBackward(_) => bracket_nesting -= 1,
-                _ => (),
-            };
-
-            bracket_nesting == 0
-        })
+/// A bracket without a partner, as the index of the offending instruction.
+struct Unmatched(char, usize);
+
+/// Recomputes jump targets after a pass has moved instructions around. Passes
+/// never unbalance brackets, so this can't fail on their output.
+pub(crate) fn relink(instrs: &mut [Spanned<Instruction>]) {
+    if compute_jumps(instrs).is_err() {
+        unreachable!("optimization pass unbalanced the program's brackets");
     }
+}
+
+fn compute_jumps(
+    instrs: &mut [Spanned<Instruction>],
+) -> Result<(), Vec<Unmatched>> {
+    use Instruction as I;
 
+    let mut open: Vec<usize> = Vec::new();
+    let mut unmatched = Vec::new();
     for pc in 0..instrs.len() {
-        let po = pc as u64;
-        instrs[pc] = match instrs[pc] {
-            I::JumpForward(_) => I::JumpForward(
-                po + find_bracket_offset(instrs[pc..].iter().copied())
-                    .ok_or(ParseError::NestingError('[', pc))?
-                    as u64,
+        match instrs[pc].inner {
+            I::JumpForward(_) | I::DefineProc(_) => open.push(pc),
+            I::JumpBackward(_) => match open.last() {
+                Some(&start)
+                    if matches!(instrs[start].inner, I::JumpForward(_)) =>
+                {
+                    open.pop();
+                    instrs[start].inner = I::JumpForward(pc as u64);
+                    instrs[pc].inner = I::JumpBackward(start as u64);
+                }
+                _ => unmatched.push(Unmatched(']', pc)),
+            },
+            I::EndProc(_) => match open.last() {
+                Some(&start)
+                    if matches!(instrs[start].inner, I::DefineProc(_)) =>
+                {
+                    open.pop();
+                    instrs[start].inner = I::DefineProc(pc as u64);
+                    instrs[pc].inner = I::EndProc(start as u64);
+                }
+                _ => unmatched.push(Unmatched(')', pc)),
+            },
+            _ => (),
+        }
+    }
+
+    if open.is_empty() && unmatched.is_empty() {
+        return Ok(());
+    }
+
+    unmatched.extend(open.into_iter().map(|pc| match instrs[pc].inner {
+        I::DefineProc(_) => Unmatched('(', pc),
+        _ => Unmatched('[', pc),
+    }));
+    unmatched.sort_unstable_by_key(|u| u.1);
+
+    Err(unmatched)
+}
+
+/// Explains each unmatched bracket, guessing at the loop that was meant to be
+/// closed from how the source is indented and how deeply the brackets nest.
+fn diagnose(
+    program: &Program,
+    instrs: &[Spanned<Instruction>],
+    unmatched: &[Unmatched],
+) -> Vec<Diagnostic> {
+    use Instruction as I;
+
+    let mut is_unmatched = vec![false; instrs.len()];
+    for u in unmatched {
+        is_unmatched[u.1] = true;
+    }
+    let lines: Vec<_> = program.lines().collect();
+    let indent_of = |pc: usize| {
+        lines
+            .get(instrs[pc].span.line - 1)
+            .and_then(|l| indentation(l))
+            .unwrap_or(0)
+    };
+    let line_of = |pc: usize| instrs[pc].span.line;
+
+    // nesting depth at every bracket, plus each loop that does line up
+    let mut depths = vec![0; instrs.len()];
+    let mut pairs = Vec::new();
+    let mut depth = 0usize;
+    for (pc, instr) in instrs.iter().enumerate() {
+        match instr.inner {
+            I::JumpForward(target) => {
+                depths[pc] = depth;
+                depth += 1;
+                if !is_unmatched[pc] {
+                    pairs.push((pc, target as usize));
+                }
+            }
+            I::JumpBackward(_) if !is_unmatched[pc] => {
+                depth -= 1;
+                depths[pc] = depth;
+            }
+            _ => (),
+        }
+    }
+
+    // a closing bracket indented differently from the opening one
+    let misaligned =
+        |(open, close): &(usize, usize)| indent_of(*open) != indent_of(*close);
+
+    let open_hint = |pc: usize| {
+        let (line, indent) = (line_of(pc), indent_of(pc));
+
+        // a nested `]` that lines up with us likely closes the wrong loop
+        let stolen = pairs.iter().filter(|p| misaligned(p)).find(|(o, c)| {
+            *o > pc && depths[*o] > depths[pc] && indent_of(*c) == indent
+        });
+        if let Some((open, close)) = stolen {
+            return format!(
+                "the `]` on line {} is indented like this loop, but closes \
+                 the one opened at line {}; did you mean to close the loop \
+                 opened at line {} before it?",
+                line_of(*close),
+                line_of(*open),
+                line_of(*open),
+            );
+        }
+
+        // otherwise the loop probably ends where the indentation does
+        let dedent = lines
+            .iter()
+            .enumerate()
+            .skip(line)
+            .find(|(_, l)| indentation(l).is_some_and(|i| i <= indent));
+        match dedent {
+            Some((i, _)) => format!(
+                "did you mean to close the loop opened at line {line} \
+                 before line {}?",
+                i + 1
             ),
-            I::JumpBackward(_) => I::JumpBackward(
-                po - find_bracket_offset(instrs[..=pc].iter().rev().copied())
-                    .ok_or(ParseError::NestingError(']', pc))?
-                    as u64,
+            None => format!(
+                "did you mean to close the loop opened at line {line} at the \
+                 end of the program?"
             ),
-            v => v,
-        };
-    }
+        }
+    };
+
+    let close_hint = |pc: usize| {
+        let indent = indent_of(pc);
+
+        // a loop at this depth and indentation that was closed elsewhere
+        let early = pairs.iter().filter(|p| misaligned(p)).rfind(|(o, c)| {
+            *c < pc && depths[*o] == depths[pc] && indent_of(*o) == indent
+        });
+        match early {
+            Some((open, close)) => format!(
+                "did you mean to close the loop opened at line {} here? the \
+                 `]` on line {} already closes it",
+                line_of(*open),
+                line_of(*close),
+            ),
+            None => "there is no open loop here; remove this `]` or add a \
+                     `[` before it"
+                .to_owned(),
+        }
+    };
 
-    Ok(())
+    unmatched
+        .iter()
+        .map(|&Unmatched(bracket, pc)| Diagnostic {
+            message: format!("missing matching brace for `{bracket}`"),
+            snippet: program.snippet(instrs[pc].span),
+            hint: Some(match bracket {
+                '[' => open_hint(pc),
+                ']' => close_hint(pc),
+                '(' => format!(
+                    "did you mean to end the procedure started at line {} \
+                     with a `)`?",
+                    line_of(pc)
+                ),
+                _ => "there is no procedure being defined here; remove this \
+                      `)` or add a `(` before it"
+                    .to_owned(),
+            }),
+        })
+        .collect()
 }
 
 impl IR {
     pub fn parse(program: &Program) -> Result<Self, ParseError> {
+        Self::parse_with_width(program, CellWidth::default())
+    }
+
+    pub fn parse_with_width(
+        program: &Program,
+        cell_width: CellWidth,
+    ) -> Result<Self, ParseError> {
         use Command as C;
         use Instruction as I;
-        let mut parsed: Vec<_> = program
+        let commands: Vec<_> = program
             .instrs
             .iter()
-            .dedup_by_with_count(|l, r| {
-                matches!(
-                    (l, r),
-                    (C::Movr, C::Movr)
-                        | (C::Movl, C::Movl)
-                        | (C::Incr, C::Incr)
-                        | (C::Decr, C::Decr)
-                )
-            })
-            .map(|(count, code)| match code {
-                C::Movr => I::ShiftRight(count as u64),
-                C::Movl => I::ShiftLeft(count as u64),
-                C::Incr => I::Add((count % 255) as u8),
-                C::Decr => I::Sub((count % 255) as u8),
-                C::Writ => I::Write,
-                C::Read => I::Read,
-                C::JmpF => I::JumpForward(0),
-                C::JmpB => I::JumpBackward(0),
+            .zip(&program.spans)
+            .map(|(code, &span)| {
+                let instr = match code {
+                    C::Movr => I::ShiftRight(1),
+                    C::Movl => I::ShiftLeft(1),
+                    C::Incr => I::Add {
+                        amount: 1,
+                        offset: 0,
+                    },
+                    C::Decr => I::Sub {
+                        amount: 1,
+                        offset: 0,
+                    },
+                    C::Writ => I::Write { offset: 0 },
+                    C::Read => I::Read { offset: 0 },
+                    C::JmpF => I::JumpForward(0),
+                    C::JmpB => I::JumpBackward(0),
+                    C::Dump => I::Debug,
+                    C::DefP => I::DefineProc(0),
+                    C::EndP => I::EndProc(0),
+                    C::Call => I::CallProc,
+                    C::Fork => I::Fork,
+                    C::Sys => I::Syscall,
+                };
+
+                Spanned::new(instr, span)
             })
             .collect();
+        let mut parsed = fold_runs(&commands, cell_width);
 
-        compute_jumps(&mut parsed[..])?;
+        compute_jumps(&mut parsed[..]).map_err(|unmatched| {
+            ParseError::NestingErrors(diagnose(program, &parsed, &unmatched))
+        })?;
 
-        Ok(IR(parsed))
+        Ok(IR {
+            instructions: parsed,
+            cell_width,
+            input: program.input.clone(),
+        })
     }
 }
@@ -0,0 +1,163 @@
+use std::fmt::{self, Display};
+
+/// A byte range in a source file, along with the line and column it starts
+/// on. Lines and columns are 1-based, columns count characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub col: usize,
+    /// The macro call the span came out of, as an index into the program's
+    /// [`Expansion`]s, or `None` if it's written out directly.
+    pub expansion: Option<usize>,
+}
+
+impl Span {
+    pub fn end(&self) -> usize {
+        self.offset + self.len
+    }
+
+    /// The smallest span covering both `self` and `other`, assuming `other`
+    /// does not start before `self`. Spans from different macro calls have
+    /// nothing in between them to cover, so `self` is kept as it is.
+    pub fn to(self, other: Span) -> Span {
+        if other.expansion != self.expansion || other.offset < self.offset {
+            return self;
+        }
+
+        Span {
+            len: other.end().max(self.end()) - self.offset,
+            ..self
+        }
+    }
+}
+
+/// A macro call, which the code in the macro's definition was expanded at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Expansion {
+    pub name: String,
+    /// From the `@` to the end of the arguments. It can come out of another
+    /// expansion itself.
+    pub call: Span,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spanned<T> {
+    pub inner: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(inner: T, span: Span) -> Self {
+        Self { inner, span }
+    }
+}
+
+/// A span resolved against the file it points into, so it can be shown to
+/// the user without holding on to the whole source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snippet {
+    pub file: String,
+    pub span: Span,
+    pub source_line: String,
+    /// The name of each macro the span was expanded from and where it was
+    /// called, innermost first.
+    pub expanded_from: Vec<(String, Snippet)>,
+}
+
+impl Snippet {
+    pub fn new(file: &str, source: &str, span: Span) -> Self {
+        let line_start = source[..span.offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[span.offset..]
+            .find('\n')
+            .map_or(source.len(), |i| span.offset + i);
+
+        Snippet {
+            file: file.to_owned(),
+            span,
+            source_line: source[line_start..line_end].to_owned(),
+            expanded_from: vec![],
+        }
+    }
+
+    /// A snippet that also points at every macro call `span` came out of.
+    pub fn expanded(
+        file: &str,
+        source: &str,
+        span: Span,
+        expansions: &[Expansion],
+    ) -> Self {
+        let mut snippet = Snippet::new(file, source, span);
+
+        let mut next = span.expansion;
+        while let Some(Expansion { name, call }) = next.map(|i| &expansions[i])
+        {
+            let call_site = Snippet::new(file, source, *call);
+            snippet.expanded_from.push((name.clone(), call_site));
+            next = call.expansion;
+        }
+
+        snippet
+    }
+
+    pub fn location(&self) -> String {
+        format!("{}:{}:{}", self.file, self.span.line, self.span.col)
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keep tabs in the padding so the caret lines up however they render
+        let padding: String = self
+            .source_line
+            .chars()
+            .take(self.span.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let visible = self.source_line.chars().count() + 1 - self.span.col;
+        let carets = "^".repeat(self.span.len.clamp(1, visible.max(1)));
+
+        write!(f, "{}\n{}{}", self.source_line, padding, carets)?;
+
+        for (name, call) in &self.expanded_from {
+            write!(
+                f,
+                "\nexpanded from `@{name}` at {}\n{call}",
+                call.location()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A single problem found in the source, pointing at where it happened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub snippet: Snippet,
+    pub hint: Option<String>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}\n{}",
+            self.snippet.location(),
+            self.message,
+            self.snippet
+        )?;
+
+        if let Some(hint) = &self.hint {
+            write!(f, "\nhint: {hint}")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+//! A human readable text format for [`IR`], one instruction per line:
+//!
+//! ```text
+//! cells u8
+//! add 8
+//! L0: loop L1
+//!     sub 1
+//!     mul_add 8 @1
+//! L1: end L0
+//! write @1
+//! ```
+//!
+//! Cells other than the current one are addressed with `@offset`. Every
+//! `loop` and `end` carries a label, and names the label of its partner as its
+//! jump target, as do a procedure's `proc` and `end_proc`. Any embedded input
+//! is given as bytes on `input` lines before the first instruction.
+//! Indentation and `;` comments are ignored when parsing.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use super::{
+    parser::{CellWidth, Instruction, IR},
+    span::{Diagnostic, Snippet, Span, Spanned},
+};
+
+#[derive(Error, Debug)]
+pub enum TextError {
+    #[error("{0}")]
+    Syntax(Box<Diagnostic>),
+}
+
+impl CellWidth {
+    fn name(self) -> &'static str {
+        match self {
+            CellWidth::U8 => "u8",
+            CellWidth::U16 => "u16",
+            CellWidth::U32 => "u32",
+            CellWidth::U64 => "u64",
+        }
+    }
+}
+
+/// ` @offset`, or nothing for the current cell.
+struct At(i64);
+
+impl Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            offset => write!(f, " @{offset}"),
+        }
+    }
+}
+
+/// The label of the jump at `pc`, or a raw `@pc` if there's no jump there to
+/// name, which the fields being public leaves open.
+struct Target<'a>(&'a HashMap<usize, usize>, u64);
+
+impl Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Target(labels, pc) = *self;
+
+        match usize::try_from(pc).ok().and_then(|pc| labels.get(&pc)) {
+            Some(label) => write!(f, "L{label}"),
+            None => write!(f, "@{pc}"),
+        }
+    }
+}
+
+impl Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction as I;
+
+        // jumps are labelled in the order they appear
+        let labels: HashMap<usize, usize> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| {
+                matches!(
+                    i.inner,
+                    I::JumpForward(_)
+                        | I::JumpBackward(_)
+                        | I::DefineProc(_)
+                        | I::EndProc(_)
+                )
+            })
+            .enumerate()
+            .map(|(label, (pc, _))| (pc, label))
+            .collect();
+
+        writeln!(f, "cells {}", self.cell_width.name())?;
+        for chunk in self.input.chunks(16) {
+            writeln!(f, "input {}", chunk.iter().join(" "))?;
+        }
+
+        let mut depth: usize = 0;
+        for (pc, instr) in self.instructions.iter().enumerate() {
+            if matches!(instr.inner, I::JumpBackward(_) | I::EndProc(_)) {
+                depth = depth.saturating_sub(1);
+            }
+            write!(f, "{}", "    ".repeat(depth))?;
+
+            match instr.inner {
+                I::ShiftLeft(n) => write!(f, "left {n}"),
+                I::ShiftRight(n) => write!(f, "right {n}"),
+                I::Add { amount, offset } => {
+                    write!(f, "add {amount}{}", At(offset))
+                }
+                I::Sub { amount, offset } => {
+                    write!(f, "sub {amount}{}", At(offset))
+                }
+                I::Set { value, offset } => {
+                    write!(f, "set {value}{}", At(offset))
+                }
+                I::MulAdd { offset, factor } => {
+                    write!(f, "mul_add {factor}{}", At(offset))
+                }
+                I::Scan { stride } => write!(f, "scan {stride}"),
+                I::Read { offset } => write!(f, "read{}", At(offset)),
+                I::Write { offset } => write!(f, "write{}", At(offset)),
+                I::Debug => write!(f, "debug"),
+                I::JumpForward(target) => {
+                    depth += 1;
+                    let target = Target(&labels, target);
+                    write!(f, "L{}: loop {target}", labels[&pc])
+                }
+                I::JumpBackward(target) => {
+                    let target = Target(&labels, target);
+                    write!(f, "L{}: end {target}", labels[&pc])
+                }
+                I::DefineProc(target) => {
+                    depth += 1;
+                    let target = Target(&labels, target);
+                    write!(f, "L{}: proc {target}", labels[&pc])
+                }
+                I::EndProc(target) => {
+                    let target = Target(&labels, target);
+                    write!(f, "L{}: end_proc {target}", labels[&pc])
+                }
+                I::CallProc => write!(f, "call"),
+                I::Fork => write!(f, "fork"),
+                I::Syscall => write!(f, "syscall"),
+            }?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A word on a line, and where it is.
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+/// Splits a line into words, leaving out any comment.
+fn tokenize(
+    line: &str,
+    line_offset: usize,
+    line_number: usize,
+) -> Vec<Token<'_>> {
+    let code = line.split(';').next().unwrap_or_default();
+
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in code.char_indices().chain([(code.len(), ' ')]) {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                tokens.push(Token {
+                    text: &code[s..i],
+                    span: Span {
+                        offset: line_offset + s,
+                        len: i - s,
+                        line: line_number,
+                        col: code[..s].chars().count() + 1,
+                        expansion: None,
+                    },
+                });
+                start = None;
+            }
+            _ => (),
+        }
+    }
+
+    tokens
+}
+
+struct TextParser<'a> {
+    name: &'a str,
+    text: &'a str,
+}
+
+impl TextParser<'_> {
+    fn error(&self, span: Span, message: impl Into<String>) -> TextError {
+        TextError::Syntax(Box::new(Diagnostic {
+            message: message.into(),
+            snippet: Snippet::new(self.name, self.text, span),
+            hint: None,
+        }))
+    }
+
+    fn number<T: FromStr>(&self, token: Token) -> Result<T, TextError> {
+        token
+            .text
+            .parse()
+            .map_err(|_| self.error(token.span, "expected a number"))
+    }
+
+    /// A value that has to fit in a cell.
+    fn value(&self, token: Token, width: CellWidth) -> Result<u64, TextError> {
+        let value = self.number(token)?;
+        if value > width.max() {
+            let message =
+                format!("{value} doesn't fit in a {} cell", width.name());
+            return Err(self.error(token.span, message));
+        }
+
+        Ok(value)
+    }
+
+    fn offset(&self, token: Option<Token>) -> Result<i64, TextError> {
+        let Some(token) = token else {
+            return Ok(0);
+        };
+
+        match token.text.strip_prefix('@') {
+            Some(offset) => offset
+                .parse()
+                .map_err(|_| self.error(token.span, "expected an offset")),
+            None => Err(self.error(token.span, "expected `@offset`")),
+        }
+    }
+
+    fn parse(&self) -> Result<IR, TextError> {
+        use Instruction as I;
+
+        let mut cell_width = None;
+        let mut input = Vec::new();
+        let mut instructions = Vec::new();
+        // label definitions, and the label each jump names as its target
+        let mut labels = HashMap::new();
+        let mut targets = Vec::new();
+
+        let mut line_offset = 0;
+        for (number, line) in self.text.split('\n').enumerate() {
+            let tokens = tokenize(line, line_offset, number + 1);
+            line_offset += line.len() + 1;
+
+            let mut tokens = &tokens[..];
+            let Some(&first) = tokens.first() else {
+                continue;
+            };
+
+            let label = first.text.strip_suffix(':');
+            if let Some(label) = label {
+                if labels.insert(label, instructions.len()).is_some() {
+                    let message = format!("label `{label}` is defined twice");
+                    return Err(self.error(first.span, message));
+                }
+                tokens = &tokens[1..];
+            }
+
+            let Some((&op, args)) = tokens.split_first() else {
+                return Err(self.error(first.span, "expected an instruction"));
+            };
+            let span = tokens[0].span.to(tokens[tokens.len() - 1].span);
+            let width = *cell_width.get_or_insert(CellWidth::U8);
+
+            // how many arguments each instruction takes, the optional one
+            // always being an offset
+            let (required, optional) = match op.text {
+                "cells" | "left" | "right" | "scan" => (1, 0),
+                "loop" | "end" | "proc" | "end_proc" => (1, 0),
+                "add" | "sub" | "set" | "mul_add" => (1, 1),
+                "read" | "write" => (0, 1),
+                "debug" | "call" | "fork" | "syscall" => (0, 0),
+                "input" => (1, args.len().saturating_sub(1)),
+                _ => {
+                    let message = format!("unknown instruction `{}`", op.text);
+                    return Err(self.error(op.span, message));
+                }
+            };
+            if args.len() < required {
+                let message = format!("`{}` is missing an argument", op.text);
+                return Err(self.error(span, message));
+            }
+            if args.len() > required + optional {
+                let extra = args[required + optional].span;
+                return Err(self.error(extra, "unexpected argument"));
+            }
+            let arg = |i: usize| args[i];
+            let at = || self.offset(args.get(required).copied());
+
+            let instr = match op.text {
+                "cells" => {
+                    if !instructions.is_empty() || label.is_some() {
+                        return Err(self.error(
+                            span,
+                            "`cells` has to come before any instruction",
+                        ));
+                    }
+                    cell_width = Some(match arg(0).text {
+                        "u8" => CellWidth::U8,
+                        "u16" => CellWidth::U16,
+                        "u32" => CellWidth::U32,
+                        "u64" => CellWidth::U64,
+                        _ => {
+                            return Err(self.error(
+                                arg(0).span,
+                                "expected `u8`, `u16`, `u32` or `u64`",
+                            ))
+                        }
+                    });
+                    continue;
+                }
+                "input" => {
+                    if !instructions.is_empty() || label.is_some() {
+                        return Err(self.error(
+                            span,
+                            "`input` has to come before any instruction",
+                        ));
+                    }
+                    for &byte in args {
+                        input.push(self.number(byte)?);
+                    }
+                    continue;
+                }
+                "left" => I::ShiftLeft(self.number(arg(0))?),
+                "right" => I::ShiftRight(self.number(arg(0))?),
+                "add" => I::Add {
+                    amount: self.value(arg(0), width)?,
+                    offset: at()?,
+                },
+                "sub" => I::Sub {
+                    amount: self.value(arg(0), width)?,
+                    offset: at()?,
+                },
+                "set" => I::Set {
+                    value: self.value(arg(0), width)?,
+                    offset: at()?,
+                },
+                "mul_add" => I::MulAdd {
+                    factor: self.value(arg(0), width)?,
+                    offset: at()?,
+                },
+                "scan" => I::Scan {
+                    stride: self.number(arg(0))?,
+                },
+                "read" => I::Read { offset: at()? },
+                "write" => I::Write { offset: at()? },
+                "debug" => I::Debug,
+                "call" => I::CallProc,
+                "fork" => I::Fork,
+                "syscall" => I::Syscall,
+                // targets are filled in once every label is known
+                "loop" | "end" | "proc" | "end_proc" => {
+                    if label.is_none() {
+                        let message = format!("`{}` needs a label", op.text);
+                        return Err(self.error(span, message));
+                    }
+                    targets.push((instructions.len(), arg(0)));
+                    match op.text {
+                        "loop" => I::JumpForward(0),
+                        "end" => I::JumpBackward(0),
+                        "proc" => I::DefineProc(0),
+                        _ => I::EndProc(0),
+                    }
+                }
+                _ => unreachable!(),
+            };
+
+            instructions.push(Spanned::new(instr, span));
+        }
+
+        self.link(&mut instructions, &labels, &targets)?;
+
+        Ok(IR {
+            instructions,
+            cell_width: cell_width.unwrap_or_default(),
+            input,
+        })
+    }
+
+    /// Points each jump at its label's instruction, checking that every
+    /// `loop` and its `end`, and every `proc` and its `end_proc`, name each
+    /// other and nest properly.
+    fn link(
+        &self,
+        instrs: &mut [Spanned<Instruction>],
+        labels: &HashMap<&str, usize>,
+        targets: &[(usize, Token)],
+    ) -> Result<(), TextError> {
+        use Instruction as I;
+
+        let mut open: Vec<usize> = Vec::new();
+        for &(pc, target) in targets {
+            let Some(&resolved) = labels.get(target.text) else {
+                let message = format!("no label called `{}`", target.text);
+                return Err(self.error(target.span, message));
+            };
+
+            let (opener, closer) = match instrs[pc].inner {
+                I::JumpForward(_) => {
+                    open.push(pc);
+                    instrs[pc].inner = I::JumpForward(resolved as u64);
+                    continue;
+                }
+                I::DefineProc(_) => {
+                    open.push(pc);
+                    instrs[pc].inner = I::DefineProc(resolved as u64);
+                    continue;
+                }
+                I::JumpBackward(_) => ("loop", "end"),
+                I::EndProc(_) => ("proc", "end_proc"),
+                _ => unreachable!(),
+            };
+
+            let Some(start) = open.pop() else {
+                let message =
+                    format!("`{closer}` without a `{opener}` to close");
+                return Err(self.error(instrs[pc].span, message));
+            };
+            let end = match (instrs[start].inner, opener) {
+                (I::JumpForward(end), "loop")
+                | (I::DefineProc(end), "proc") => end,
+                _ => {
+                    let message = format!(
+                        "`{closer}` can't close the `{}` on line {}",
+                        if opener == "loop" { "proc" } else { "loop" },
+                        instrs[start].span.line
+                    );
+                    return Err(self.error(instrs[pc].span, message));
+                }
+            };
+            if resolved != start || end as usize != pc {
+                let message = format!(
+                    "this `{closer}` closes the `{opener}` on line {}, so they \
+                     have to name each other's labels",
+                    instrs[start].span.line
+                );
+                return Err(self.error(instrs[pc].span, message));
+            }
+            instrs[pc].inner = match opener {
+                "loop" => I::JumpBackward(resolved as u64),
+                _ => I::EndProc(resolved as u64),
+            };
+        }
+
+        match open.pop() {
+            Some(pc) => {
+                let op = match instrs[pc].inner {
+                    I::DefineProc(_) => "proc",
+                    _ => "loop",
+                };
+                Err(self.error(instrs[pc].span, format!("`{op}` never ends")))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl IR {
+    /// Parses the text format, with `name` as the file name in errors.
+    pub fn from_text(name: &str, text: &str) -> Result<IR, TextError> {
+        TextParser { name, text }.parse()
+    }
+}
+
+impl FromStr for IR {
+    type Err = TextError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        IR::from_text("<ir>", s)
+    }
+}
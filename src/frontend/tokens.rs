@@ -0,0 +1,429 @@
+//! Frontends for languages that are Brainfuck spelled differently, like Ook!
+//! and Blub. A [`TokenTable`] says which token each command is written as,
+//! reads source written with it into a [`Program`], and renders programs
+//! back out with it.
+
+use std::{cmp::Reverse, collections::HashSet, iter};
+
+use thiserror::Error;
+
+use super::{
+    parser::{
+        CellWidth, Command, Dialect, Instruction, Program, IR, TAPE_LENGTH,
+    },
+    span::{Span, Spanned},
+};
+
+/// Rendered lines are broken before they get longer than this.
+const LINE_WIDTH: usize = 80;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TableError {
+    #[error("the token for `{}` is empty", .0.char())]
+    EmptyToken(Command),
+    #[error("`{0}` is the token for more than one command")]
+    DuplicateToken(String),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RenderError {
+    #[error("the table has no token for `{}`", .0.char())]
+    MissingToken(Command),
+    #[error(
+        "the `mul_add` at instruction {0} doesn't clear the current cell \
+         afterwards, so it can't be written as a loop"
+    )]
+    UnclearedMulAdd(usize),
+}
+
+/// The tokens a language writes each command as. A token can be several
+/// words, and any run of whitespace in the source separates them. A command
+/// can have more than one token, in which case it's rendered with the first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenTable {
+    /// Each token split into its words.
+    tokens: Vec<(Command, Vec<String>)>,
+    /// What goes between tokens when rendering.
+    separator: String,
+}
+
+impl TokenTable {
+    pub fn new<S: AsRef<str>>(
+        tokens: impl IntoIterator<Item = (Command, S)>,
+    ) -> Result<Self, TableError> {
+        let mut table = Vec::new();
+        let mut seen = HashSet::new();
+
+        for (command, token) in tokens {
+            let words: Vec<_> = token
+                .as_ref()
+                .split_whitespace()
+                .map(str::to_owned)
+                .collect();
+            if words.is_empty() {
+                return Err(TableError::EmptyToken(command));
+            }
+            if !seen.insert(words.clone()) {
+                return Err(TableError::DuplicateToken(words.join(" ")));
+            }
+
+            table.push((command, words));
+        }
+
+        Ok(TokenTable {
+            tokens: table,
+            separator: " ".to_owned(),
+        })
+    }
+
+    /// Separates rendered tokens with `separator` rather than a space.
+    pub fn with_separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_owned();
+        self
+    }
+
+    /// Plain Brainfuck, without any dialect.
+    pub fn brainfuck() -> Self {
+        let tokens = Command::ALL
+            .into_iter()
+            .filter(|c| c.dialect().is_empty())
+            .map(|c| (c, c.char().to_string()));
+
+        Self::new(tokens)
+            .expect("every command has its own character")
+            .with_separator("")
+    }
+
+    pub fn ook() -> Self {
+        Self::pairs("Ook")
+    }
+
+    pub fn blub() -> Self {
+        Self::pairs("Blub")
+    }
+
+    /// Boolfuck, which only has the commands that make sense on a tape of
+    /// bits, with `+` flipping the current bit and `;` writing it. Programs
+    /// read with it are parsed into a [`BitIR`](super::bits::BitIR).
+    pub fn boolfuck() -> Self {
+        Self::new([
+            (Command::Movr, ">"),
+            (Command::Movl, "<"),
+            (Command::Incr, "+"),
+            (Command::Writ, ";"),
+            (Command::Read, ","),
+            (Command::JmpF, "["),
+            (Command::JmpB, "]"),
+        ])
+        .expect("every command has its own character")
+        .with_separator("")
+    }
+
+    /// Ook! and the languages copying it write each command as a pair of
+    /// the same word, punctuated differently.
+    fn pairs(word: &str) -> Self {
+        let [dot, question, bang] =
+            [".", "?", "!"].map(|p| word.to_owned() + p);
+        let pair = |a: &str, b: &str| format!("{a} {b}");
+
+        Self::new([
+            (Command::Movr, pair(&dot, &question)),
+            (Command::Movl, pair(&question, &dot)),
+            (Command::Incr, pair(&dot, &dot)),
+            (Command::Decr, pair(&bang, &bang)),
+            (Command::Writ, pair(&bang, &dot)),
+            (Command::Read, pair(&dot, &bang)),
+            (Command::JmpF, pair(&bang, &question)),
+            (Command::JmpB, pair(&question, &bang)),
+        ])
+        .expect("every pair is different")
+    }
+
+    /// The token `command` is rendered as.
+    pub fn token(&self, command: Command) -> Option<String> {
+        self.tokens
+            .iter()
+            .find(|(c, _)| *c == command)
+            .map(|(_, words)| words.join(" "))
+    }
+
+    /// Reads source written with this table. Anything that isn't a token is
+    /// a comment. `#` is only a command if the table has a token for it, in
+    /// which case the program is in the debug dump dialect.
+    pub fn tokenize(&self, name: &str, source: &str) -> Program {
+        let mut commands = Vec::new();
+        let mut dialect = Dialect::empty();
+
+        // the longest token wins when one starts with another
+        let mut tokens: Vec<_> = self.tokens.iter().collect();
+        tokens.sort_by_key(|(_, words)| {
+            Reverse(words.iter().map(String::len).sum::<usize>())
+        });
+
+        let (mut offset, mut line, mut col) = (0, 1, 1);
+        while offset < source.len() {
+            let rest = &source[offset..];
+            let found = tokens.iter().find_map(|(command, words)| {
+                Some((*command, match_words(rest, words)?))
+            });
+
+            let len = match found {
+                Some((command, len)) => {
+                    let span = Span {
+                        offset,
+                        len,
+                        line,
+                        col,
+                        expansion: None,
+                    };
+                    commands.push(Spanned::new(command, span));
+                    dialect |= command.dialect();
+                    len
+                }
+                None => rest.chars().next().map_or(1, char::len_utf8),
+            };
+
+            for c in rest[..len].chars() {
+                if c == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
+            }
+            offset += len;
+        }
+
+        Program::from_commands(name, source, dialect, commands)
+    }
+
+    /// Writes out `commands` with this table, breaking lines before they get
+    /// too long.
+    pub fn render(&self, commands: &[Command]) -> Result<String, RenderError> {
+        let mut out = String::new();
+        let mut line = 0;
+
+        for &command in commands {
+            let token = self
+                .token(command)
+                .ok_or(RenderError::MissingToken(command))?;
+            let len = token.chars().count();
+
+            if line > 0 {
+                let separator = self.separator.chars().count();
+                if line + separator + len > LINE_WIDTH {
+                    out.push('\n');
+                    line = 0;
+                } else {
+                    out += &self.separator;
+                    line += separator;
+                }
+            }
+            out += &token;
+            line += len;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        Ok(out)
+    }
+
+    /// Writes out a program's commands with this table. Embedded input isn't
+    /// part of the rendered program.
+    pub fn render_program(
+        &self,
+        program: &Program,
+    ) -> Result<String, RenderError> {
+        self.render(program.commands())
+    }
+
+    /// Writes out `ir` with this table, turning each instruction back into
+    /// the commands it stands for. `MulAdd`s can only be written as a loop
+    /// when the current cell is cleared right after them, like the
+    /// optimizer leaves them.
+    pub fn render_ir(&self, ir: &IR) -> Result<String, RenderError> {
+        self.render(&commands(ir)?)
+    }
+}
+
+/// How many bytes at the start of `text` spell out `words`, if it does.
+fn match_words(text: &str, words: &[String]) -> Option<usize> {
+    let mut len = 0;
+
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            let rest = &text[len..];
+            let gap = rest.len() - rest.trim_start().len();
+            if gap == 0 {
+                return None;
+            }
+            len += gap;
+        }
+
+        if !text[len..].starts_with(word.as_str()) {
+            return None;
+        }
+        len += word.len();
+    }
+
+    Some(len)
+}
+
+/// Brainfuck commands for [`IR`], building them up with the pointer lagging
+/// behind the instructions until it's needed, the reverse of
+/// [`defer_movement`](super::optimizer::defer_movement).
+struct Commands {
+    out: Vec<Command>,
+    width: CellWidth,
+    /// Where the real pointer is, relative to where the IR's would be.
+    here: i64,
+}
+
+impl Commands {
+    fn push(&mut self, command: Command, times: u64) {
+        self.out.extend(iter::repeat_n(command, times as usize));
+    }
+
+    /// Moves the pointer `offset` cells away from where the IR's would be,
+    /// the short way around the tape.
+    fn move_to(&mut self, offset: i64) {
+        let len = TAPE_LENGTH as i64;
+        let distance = (offset - self.here).rem_euclid(len);
+
+        if distance <= len / 2 {
+            self.push(Command::Movr, distance as u64);
+        } else {
+            self.push(Command::Movl, (len - distance) as u64);
+        }
+        self.here = offset;
+    }
+
+    /// The IR's pointer moving by `by` leaves the real one behind.
+    fn shift(&mut self, by: i64) {
+        self.here = (self.here - by).rem_euclid(TAPE_LENGTH as i64);
+    }
+
+    /// Adds `amount` to the current cell, counting down instead if that's
+    /// shorter.
+    fn add(&mut self, amount: u64) {
+        let down = self.width.wrap(amount.wrapping_neg());
+
+        if amount <= down {
+            self.push(Command::Incr, amount);
+        } else {
+            self.push(Command::Decr, down);
+        }
+    }
+}
+
+fn commands(ir: &IR) -> Result<Vec<Command>, RenderError> {
+    use Command as C;
+    use Instruction as I;
+
+    let instrs = &ir.instructions;
+    let mut out = Commands {
+        out: Vec::with_capacity(instrs.len()),
+        width: ir.cell_width,
+        here: 0,
+    };
+
+    let mut pc = 0;
+    while pc < instrs.len() {
+        match instrs[pc].inner {
+            I::ShiftLeft(n) => out.shift(-((n % TAPE_LENGTH as u64) as i64)),
+            I::ShiftRight(n) => out.shift((n % TAPE_LENGTH as u64) as i64),
+            I::Add { amount, offset } => {
+                out.move_to(offset);
+                out.add(amount);
+            }
+            I::Sub { amount, offset } => {
+                out.move_to(offset);
+                out.add(ir.cell_width.wrap(amount.wrapping_neg()));
+            }
+            I::Set { value, offset } => {
+                out.move_to(offset);
+                out.out.extend([C::JmpF, C::Decr, C::JmpB]);
+                out.add(value);
+            }
+            I::MulAdd { .. } => {
+                let end = instrs[pc..]
+                    .iter()
+                    .position(|i| !matches!(i.inner, I::MulAdd { .. }))
+                    .map_or(instrs.len(), |len| pc + len);
+                let Some(I::Set { value, offset: 0 }) =
+                    instrs.get(end).map(|i| i.inner)
+                else {
+                    return Err(RenderError::UnclearedMulAdd(pc));
+                };
+
+                out.move_to(0);
+                out.out.extend([C::JmpF, C::Decr]);
+                for instr in &instrs[pc..end] {
+                    let I::MulAdd { offset, factor } = instr.inner else {
+                        unreachable!();
+                    };
+                    out.move_to(offset);
+                    out.add(factor);
+                }
+                out.move_to(0);
+                out.out.push(C::JmpB);
+                out.add(value);
+
+                pc = end + 1;
+                continue;
+            }
+            I::Scan { stride } => {
+                out.move_to(0);
+                out.out.push(C::JmpF);
+                out.move_to(stride);
+                out.here = 0;
+                out.out.push(C::JmpB);
+            }
+            I::Read { offset } => {
+                out.move_to(offset);
+                out.out.push(C::Read);
+            }
+            I::Write { offset } => {
+                out.move_to(offset);
+                out.out.push(C::Writ);
+            }
+            I::JumpForward(_) => {
+                out.move_to(0);
+                out.out.push(C::JmpF);
+            }
+            I::JumpBackward(_) => {
+                out.move_to(0);
+                out.out.push(C::JmpB);
+            }
+            I::Debug => {
+                out.move_to(0);
+                out.out.push(C::Dump);
+            }
+            I::DefineProc(_) => {
+                out.move_to(0);
+                out.out.push(C::DefP);
+            }
+            I::EndProc(_) => {
+                out.move_to(0);
+                out.out.push(C::EndP);
+            }
+            I::CallProc => {
+                out.move_to(0);
+                out.out.push(C::Call);
+            }
+            I::Fork => {
+                out.move_to(0);
+                out.out.push(C::Fork);
+            }
+            I::Syscall => {
+                out.move_to(0);
+                out.out.push(C::Sys);
+            }
+        }
+
+        pc += 1;
+    }
+
+    Ok(out.out)
+}
@@ -0,0 +1,361 @@
+//! Runs [`IR`] directly, with the same semantics as the compiled code: a
+//! wrapping tape of [`TAPE_LENGTH`] cells, `.` writing the low byte of a cell
+//! and `,` reading a byte into it, from the embedded input until it runs out.
+//! Processes forked with `Y` run one after another once their parent
+//! finishes, the way the compiled code waits for them before exiting. A `%`
+//! syscall is an error, since making it for real would hand the program this
+//! whole process, interpreter and all.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use thiserror::Error;
+
+use crate::{
+    backend::compiler::EofBehavior,
+    frontend::parser::{
+        CellWidth, Instruction, CALL_DEPTH_LIMIT, IR, PROCEDURE_COUNT,
+        TAPE_LENGTH,
+    },
+};
+
+/// How many cells either side of the pointer a `#` dump shows.
+pub const DUMP_RADIUS: usize = 4;
+
+/// What `#` dumps, which the compiled code writes to stderr: the index of
+/// the current cell, then the cells around it in hex with the current one in
+/// brackets.
+pub fn dump(tape: &[u64], pointer: usize, width: CellWidth) -> String {
+    let digits = 2 * width.bytes() as usize;
+    let len = tape.len();
+
+    let mut line = format!("ptr {pointer:04x}:");
+    for i in 0..=2 * DUMP_RADIUS {
+        let cell = tape[(pointer + len - DUMP_RADIUS + i) % len];
+        line += &match i {
+            DUMP_RADIUS => format!(" [{cell:0digits$x}]"),
+            _ => format!(" {cell:0digits$x}"),
+        };
+    }
+    line.push('\n');
+
+    line
+}
+
+#[derive(Error, Debug)]
+pub enum InterpError {
+    #[error("could not read input: {0}")]
+    Read(io::Error),
+    #[error("could not write output: {0}")]
+    Write(io::Error),
+    #[error(
+        "can't define procedure {0}, procedures are numbered below \
+         {PROCEDURE_COUNT}"
+    )]
+    ProcedureNumber(u64),
+    #[error("procedure {0} is called before it's defined")]
+    UndefinedProcedure(u64),
+    #[error("more than {CALL_DEPTH_LIMIT} procedure calls are in progress")]
+    CallDepth,
+    #[error("`%` can only make a syscall from compiled code")]
+    Syscall,
+}
+
+/// Whether there's anything left to run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Finished,
+}
+
+/// What the program left behind once it finished.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Execution {
+    /// Every byte the program wrote, followed by whatever the processes it
+    /// forked wrote.
+    pub output: Vec<u8>,
+    /// Every `#` dump, in the same order.
+    pub dumps: Vec<u8>,
+    pub tape: Vec<u64>,
+    pub pointer: usize,
+}
+
+/// A program part way through running, one step at a time.
+#[derive(Clone, Debug)]
+pub struct Machine<'ir> {
+    ir: &'ir IR,
+    eof: EofBehavior,
+    tape: Vec<u64>,
+    pointer: usize,
+    pc: usize,
+    /// How much of the embedded input has been read.
+    input: usize,
+    output: Vec<u8>,
+    /// Every `#` dump so far, kept rather than written anywhere so the
+    /// caller decides where they go.
+    dumps: Vec<u8>,
+    /// Where each procedure defined so far starts, as the index of its
+    /// `DefineProc`.
+    procedures: Vec<Option<usize>>,
+    /// The index of every `CallProc` still waiting for its procedure to
+    /// return.
+    calls: Vec<usize>,
+    /// Processes forked off that haven't run yet.
+    forks: Vec<Machine<'ir>>,
+}
+
+impl<'ir> Machine<'ir> {
+    pub fn new(ir: &'ir IR, eof: EofBehavior) -> Self {
+        Machine {
+            ir,
+            eof,
+            tape: vec![0; TAPE_LENGTH as usize],
+            pointer: 0,
+            pc: 0,
+            input: 0,
+            output: vec![],
+            dumps: vec![],
+            procedures: vec![None; PROCEDURE_COUNT as usize],
+            calls: vec![],
+            forks: vec![],
+        }
+    }
+
+    pub fn ir(&self) -> &'ir IR {
+        self.ir
+    }
+
+    pub fn tape(&self) -> &[u64] {
+        &self.tape
+    }
+
+    /// Index of the current cell.
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Index of the next instruction to run.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn dumps(&self) -> &[u8] {
+        &self.dumps
+    }
+
+    /// Processes forked off by this one, which run once it's finished.
+    pub fn forks(&self) -> &[Machine<'ir>] {
+        &self.forks
+    }
+
+    /// How many procedure calls are in progress.
+    pub fn call_depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// What's left of the input embedded in the program.
+    pub fn embedded_input(&self) -> &'ir [u8] {
+        &self.ir.input[self.input..]
+    }
+
+    /// The next instruction to run, or `None` once the program is finished.
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        self.ir.instructions.get(self.pc).map(|i| i.inner)
+    }
+
+    pub fn status(&self) -> Status {
+        match self.next_instruction() {
+            Some(_) => Status::Running,
+            None => Status::Finished,
+        }
+    }
+
+    /// Index of the cell `offset` away from the pointer.
+    pub fn address(&self, offset: i64) -> usize {
+        (self.pointer as i64 + offset).rem_euclid(TAPE_LENGTH as i64) as usize
+    }
+
+    fn cell(&mut self, offset: i64) -> &mut u64 {
+        let address = self.address(offset);
+        &mut self.tape[address]
+    }
+
+    fn shift(&mut self, by: i64) {
+        self.pointer = self.address(by);
+    }
+
+    /// Runs the next instruction. A `Scan` moves one stride per step, so no
+    /// step takes more than a fixed amount of work.
+    pub fn step(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<Status, InterpError> {
+        use Instruction as I;
+
+        let Some(instr) = self.next_instruction() else {
+            return Ok(Status::Finished);
+        };
+        let width = self.ir.cell_width;
+
+        match instr {
+            I::ShiftLeft(n) => self.shift(-(n as i64)),
+            I::ShiftRight(n) => self.shift(n as i64),
+            I::Add { amount, offset } => {
+                let cell = self.cell(offset);
+                *cell = width.wrap(cell.wrapping_add(amount));
+            }
+            I::Sub { amount, offset } => {
+                let cell = self.cell(offset);
+                *cell = width.wrap(cell.wrapping_sub(amount));
+            }
+            I::Set { value, offset } => *self.cell(offset) = value,
+            I::MulAdd { offset, factor } => {
+                let value = *self.cell(0);
+                let cell = self.cell(offset);
+                *cell =
+                    width.wrap(cell.wrapping_add(value.wrapping_mul(factor)));
+            }
+            I::Scan { stride } => {
+                if *self.cell(0) != 0 {
+                    self.shift(stride);
+                    return Ok(Status::Running);
+                }
+            }
+            I::Read { offset } if !self.embedded_input().is_empty() => {
+                let byte = self.embedded_input()[0];
+                self.input += 1;
+                *self.cell(offset) = byte as u64;
+            }
+            I::Read { offset } => {
+                let mut byte = [0];
+                let read = loop {
+                    match input.read(&mut byte) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        result => break result.map_err(InterpError::Read)?,
+                    }
+                };
+                let eof = self.eof;
+                let cell = self.cell(offset);
+                match (read, eof) {
+                    (0, EofBehavior::Unchanged) => (),
+                    (0, EofBehavior::Zero) => *cell = 0,
+                    (0, EofBehavior::MaxValue) => *cell = width.max(),
+                    _ => *cell = byte[0] as u64,
+                }
+            }
+            I::Write { offset } => {
+                let byte = *self.cell(offset) as u8;
+                output.write_all(&[byte]).map_err(InterpError::Write)?;
+                self.output.push(byte);
+            }
+            I::JumpForward(target) => {
+                if *self.cell(0) == 0 {
+                    self.pc = target as usize;
+                }
+            }
+            I::JumpBackward(target) => {
+                if *self.cell(0) != 0 {
+                    self.pc = target as usize;
+                }
+            }
+            I::Debug => {
+                let dump = dump(&self.tape, self.pointer, width);
+                self.dumps.extend(dump.as_bytes());
+            }
+            I::DefineProc(end) => {
+                let number = *self.cell(0);
+                let procedure = self
+                    .procedures
+                    .get_mut(number as usize)
+                    .ok_or(InterpError::ProcedureNumber(number))?;
+                *procedure = Some(self.pc);
+                self.pc = end as usize;
+            }
+            I::EndProc(_) => {
+                self.pc = self
+                    .calls
+                    .pop()
+                    .expect("procedures are only run by calling them");
+            }
+            I::CallProc => {
+                let number = *self.cell(0);
+                let start = self
+                    .procedures
+                    .get(number as usize)
+                    .copied()
+                    .flatten()
+                    .ok_or(InterpError::UndefinedProcedure(number))?;
+                if self.calls.len() == CALL_DEPTH_LIMIT {
+                    return Err(InterpError::CallDepth);
+                }
+                self.calls.push(self.pc);
+                self.pc = start;
+            }
+            I::Fork => {
+                let mut child = Machine {
+                    output: vec![],
+                    dumps: vec![],
+                    forks: vec![],
+                    ..self.clone()
+                };
+                child.shift(1);
+                *child.cell(0) = 1;
+                child.pc += 1;
+                self.forks.push(child);
+
+                *self.cell(0) = 0;
+            }
+            I::Syscall => return Err(InterpError::Syscall),
+        }
+
+        self.pc += 1;
+        Ok(self.status())
+    }
+
+    /// Steps until the program finishes, then runs every process it forked.
+    pub fn run(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), InterpError> {
+        while self.step(input, output)? == Status::Running {}
+
+        for mut fork in mem::take(&mut self.forks) {
+            fork.run(input, output)?;
+            self.output.extend(fork.output);
+            self.dumps.extend(fork.dumps);
+        }
+
+        output.flush().map_err(InterpError::Write)
+    }
+
+    pub fn into_execution(self) -> Execution {
+        Execution {
+            output: self.output,
+            dumps: self.dumps,
+            tape: self.tape,
+            pointer: self.pointer,
+        }
+    }
+}
+
+/// Runs the program to the end, reading from `input` and writing to
+/// `output`.
+pub fn run(
+    ir: &IR,
+    mut input: impl Read,
+    mut output: impl Write,
+    eof: EofBehavior,
+) -> Result<Execution, InterpError> {
+    let mut machine = Machine::new(ir, eof);
+    machine.run(&mut input, &mut output)?;
+
+    Ok(machine.into_execution())
+}
@@ -1,3 +1,5 @@
 pub mod backend;
+pub mod debugger;
 pub mod frontend;
+pub mod interp;
 pub mod test_helpers;
@@ -2,7 +2,7 @@ use std::{
     fs::{File, Permissions},
     io::Write,
     os::{fd::AsRawFd, unix::fs::PermissionsExt},
-    process::{Command, Output},
+    process::{Command, Output, Stdio},
     thread::sleep,
     time::Duration,
 };
@@ -10,6 +10,10 @@ use std::{
 use tempdir::TempDir;
 
 pub fn create_and_run_bin(binary: &[u8]) -> Output {
+    create_and_run_bin_with_input(binary, &[])
+}
+
+pub fn create_and_run_bin_with_input(binary: &[u8], input: &[u8]) -> Output {
     let dir = TempDir::new("hello_world").unwrap();
 
     let elf_path = dir.path().join("elf");
@@ -30,5 +34,17 @@ pub fn create_and_run_bin(binary: &[u8]) -> Output {
     unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) };
     drop(file);
 
-    Command::new(elf_path).output().unwrap()
+    let mut child = Command::new(elf_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // dropping stdin once written closes the pipe, so the program sees EOF.
+    // A program that exits without reading everything breaks the pipe, which
+    // is fine
+    let _ = child.stdin.take().unwrap().write_all(input);
+
+    child.wait_with_output().unwrap()
 }
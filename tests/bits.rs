@@ -0,0 +1,110 @@
+use concussion::backend::bits::compile;
+use concussion::frontend::bits::{BitIR, BitInstruction, BIT_TAPE_LENGTH};
+use concussion::frontend::parser::{Dialect, ParseError, Program};
+use concussion::frontend::tokens::TokenTable;
+use concussion::test_helpers::create_and_run_bin_with_input;
+use pretty_assertions::assert_eq;
+
+fn parse(source: &str) -> BitIR {
+    let program = TokenTable::boolfuck().tokenize("<bits>", source);
+    BitIR::parse(&program).unwrap()
+}
+
+fn run(source: &str, input: &[u8]) -> Vec<u8> {
+    let binary = compile(parse(source)).unwrap();
+    create_and_run_bin_with_input(&binary, input).stdout
+}
+
+/// Boolfuck writing `bytes`, flipping the current bit as needed.
+fn printer(bytes: &[u8]) -> String {
+    let mut source = String::new();
+    let mut bit = false;
+    for byte in bytes {
+        for i in 0..8 {
+            if (byte >> i & 1 == 1) != bit {
+                source.push('+');
+                bit = !bit;
+            }
+            source.push(';');
+        }
+    }
+    source
+}
+
+#[test]
+fn lowers_to_bits() {
+    use BitInstruction as B;
+
+    // `.` and `-` are comments, and an even number of flips cancel out
+    let ir = parse("+.>>;++<[-,]");
+    let instrs: Vec<_> = ir.instructions.iter().map(|i| i.inner).collect();
+    assert_eq!(
+        instrs,
+        [
+            B::Flip,
+            B::Shift(2),
+            B::Write,
+            B::Shift(-1),
+            B::JumpForward(6),
+            B::Read,
+            B::JumpBackward(4),
+        ]
+    );
+    assert_eq!(ir.instructions[3].span.col, 8);
+
+    let lap = ">".repeat(BIT_TAPE_LENGTH as usize + 3);
+    assert_eq!(parse(&lap).instructions[0].inner, B::Shift(3));
+
+    let program = Program::with_dialect("<bits>", "+#>#", Dialect::DEBUG_DUMP);
+    let Err(ParseError::Unsupported(diagnostics)) = BitIR::parse(&program)
+    else {
+        panic!("expected `#` to be unsupported");
+    };
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "<bits>:1:2: `#` has no meaning on a bit tape\n+#>#\n ^"
+    );
+
+    // Boolfuck has no `-`, so one only turns up in a program read some other
+    // way, like plain Brainfuck
+    let Err(ParseError::Unsupported(diagnostics)) =
+        BitIR::parse(&Program::new("<bits>", "+>-"))
+    else {
+        panic!("expected `-` to be unsupported");
+    };
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "<bits>:1:3: `-` has no meaning on a bit tape\n+>-\n  ^"
+    );
+}
+
+#[test]
+fn writes_bytes_lsb_first() {
+    let hello = b"Hello, World!\n";
+    assert_eq!(run(&printer(hello), b""), hello);
+
+    // a last partial byte is padded with zeros
+    assert_eq!(run("+;", b""), [1]);
+    assert_eq!(run("+>+>+<<[;>]", b""), [7]);
+    assert_eq!(run(&format!("{};+;", "+".repeat(300)), b""), [2]);
+}
+
+#[test]
+fn reads_bytes_lsb_first() {
+    let echo = ",;".repeat(16);
+    assert_eq!(run(&echo, b"hi"), b"hi");
+
+    // every bit past the end of the input is 0
+    assert_eq!(run(&echo, b"h"), b"h\0");
+    assert_eq!(run(&format!("+{echo}"), b""), [0, 0]);
+}
+
+#[test]
+fn tape_wraps() {
+    // one bit left of the start, then back round to the start
+    assert_eq!(run("<+><[;>]", b""), [1]);
+
+    let lap = ">".repeat(BIT_TAPE_LENGTH as usize);
+    assert_eq!(run(&format!("+{lap};"), b""), [1]);
+}
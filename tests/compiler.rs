@@ -0,0 +1,463 @@
+use concussion::backend::compiler::{
+    compile_with_options, CompilerOptions, EofBehavior,
+};
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{
+    CellWidth, Dialect, Instruction, Program, IR,
+};
+use concussion::frontend::span::Spanned;
+use concussion::interp;
+use concussion::test_helpers::create_and_run_bin_with_input;
+use pretty_assertions::assert_eq;
+
+const WIDTHS: [CellWidth; 4] = [
+    CellWidth::U8,
+    CellWidth::U16,
+    CellWidth::U32,
+    CellWidth::U64,
+];
+
+fn run(source: &str, input: &[u8], options: CompilerOptions) -> Vec<u8> {
+    run_with_width(source, input, CellWidth::U8, options)
+}
+
+fn run_with_width(
+    source: &str,
+    input: &[u8],
+    width: CellWidth,
+    options: CompilerOptions,
+) -> Vec<u8> {
+    let program = source.into();
+    let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+    let binary = compile_with_options(ir, options).unwrap();
+
+    create_and_run_bin_with_input(&binary, input).stdout
+}
+
+#[test]
+fn cat() {
+    let options = CompilerOptions {
+        eof: EofBehavior::Zero,
+        ..Default::default()
+    };
+
+    assert_eq!(run(",[.,]", b"Hello world!\n", options), b"Hello world!\n");
+}
+
+#[test]
+fn eof_behaviors() {
+    // the cell holds 5 before the read hits EOF
+    const SOURCE: &str = "+++++,.";
+
+    let expected = [
+        (EofBehavior::Unchanged, 5u8),
+        (EofBehavior::Zero, 0),
+        (EofBehavior::MaxValue, 255),
+    ];
+
+    for (eof, cell) in expected {
+        let output = run(
+            SOURCE,
+            b"",
+            CompilerOptions {
+                eof,
+                ..Default::default()
+            },
+        );
+        assert_eq!(output, [cell], "{eof:?}");
+    }
+}
+
+#[test]
+fn wide_cells() {
+    // 256 in the first cell, then print '1' if it's still nonzero
+    let source =
+        format!("++++++++[>++++++++<-]>[<++++>-]<[>{}.<[-]]", "+".repeat(49));
+
+    for width in WIDTHS {
+        let output = run_with_width(&source, b"", width, Default::default());
+        let expected: &[u8] = if width == CellWidth::U8 { b"" } else { b"1" };
+        assert_eq!(output, expected, "{width:?}");
+    }
+}
+
+#[test]
+fn wide_reads() {
+    // prints '1' if the cell isn't zero once 'A' is taken back off, or if
+    // it isn't -1 after reading at EOF
+    let check = format!("[>{}.<[-]]", "+".repeat(49));
+    let options = CompilerOptions {
+        eof: EofBehavior::MaxValue,
+        ..Default::default()
+    };
+
+    for width in WIDTHS {
+        let source = format!("-,{}{check}", "-".repeat(65));
+        let output = run_with_width(&source, b"A", width, options);
+        assert_eq!(output, b"", "{width:?}");
+
+        let source = format!(",+{check}");
+        let output = run_with_width(&source, b"", width, options);
+        assert_eq!(output, b"", "{width:?}");
+    }
+}
+
+#[test]
+fn wide_scans() {
+    // 1 next to 256 in both directions, which is 0 in the low byte
+    let right = format!("+>>++++++++[<{}>-]<<[>]<<", "+".repeat(32));
+    let left = format!("+<<++++++++[>{}<-]>>[<]>>", "+".repeat(32));
+    let print = format!("[{}.[-]]", "+".repeat(64));
+
+    for width in WIDTHS {
+        let expected: &[u8] = if width == CellWidth::U8 { b"" } else { b"A" };
+        for scan in [&right, &left] {
+            let source = format!("{scan}{print}");
+            let output =
+                run_with_width(&source, b"", width, Default::default());
+            assert_eq!(output, expected, "{width:?} {scan}");
+        }
+    }
+}
+
+#[test]
+fn large_immediates() {
+    use Instruction as I;
+
+    // the loop only ends if every large immediate cancels out exactly
+    let instructions = [
+        I::Set {
+            value: 1 << 40,
+            offset: 0,
+        },
+        I::Add {
+            amount: 1 << 33,
+            offset: 0,
+        },
+        I::JumpForward(6),
+        I::Add {
+            amount: 0x41,
+            offset: 0,
+        },
+        I::Write { offset: 0 },
+        I::Sub {
+            amount: (1 << 40) + (1 << 33) + 0x41,
+            offset: 0,
+        },
+        I::JumpBackward(2),
+    ];
+    let ir = IR {
+        instructions: instructions
+            .into_iter()
+            .map(|i| Spanned::new(i, Default::default()))
+            .collect(),
+        cell_width: CellWidth::U64,
+        input: vec![],
+    };
+
+    let binary = compile_with_options(ir, Default::default()).unwrap();
+    let output = create_and_run_bin_with_input(&binary, b"").stdout;
+    assert_eq!(output, b"A");
+}
+
+#[test]
+fn compile_time_evaluation() {
+    let mut scans: String = (1..=20).map(|n| "+".repeat(n) + ">").collect();
+    scans += &"<".repeat(20);
+    scans += "[>]<.[<]>.[-]<++[>]<.>+>[<]>.>.";
+
+    let sources = [
+        "+++++++[->+++++++++<<+>]>++.<<[->>>+<<<]>>>.",
+        "++++++++[>++++++++<-]>+.<<+<++>>>.<<<[>>>+.<<<-]>>[<+>-]<.",
+        &scans,
+    ];
+
+    for source in sources {
+        let expected = run(source, b"", Default::default());
+
+        // running out part way through has to resume at every sort of
+        // instruction, including in the middle of a scan
+        for fuel in (0..300).step_by(7).chain([u64::MAX]) {
+            let options = CompilerOptions {
+                fuel: Some(fuel),
+                ..Default::default()
+            };
+            let output = run(source, b"", options);
+            assert_eq!(output, expected, "{source} with {fuel} fuel");
+        }
+    }
+}
+
+#[test]
+fn partial_evaluation() {
+    // a prompt and some constants are set up before the first read, which
+    // happens inside a loop in the second program
+    let sources = [
+        "++++++++[>++++++++<-]>+.>++.<<,[.,]",
+        "++++++++[>++++++++<-]>+.>+[,.]",
+    ];
+
+    for source in sources {
+        let options = CompilerOptions {
+            eof: EofBehavior::Zero,
+            ..Default::default()
+        };
+        let expected = run(source, b"Hi", options);
+
+        for fuel in (0..60).step_by(3).chain([u64::MAX]) {
+            let options = CompilerOptions {
+                fuel: Some(fuel),
+                ..options
+            };
+            let output = run(source, b"Hi", options);
+            assert_eq!(output, expected, "{source} with {fuel} fuel");
+        }
+    }
+}
+
+#[test]
+fn read_across_tape_edge() {
+    // the read goes through the wrapping path, and ends the block
+    let options = CompilerOptions {
+        eof: EofBehavior::Zero,
+        ..Default::default()
+    };
+
+    assert_eq!(run("+.<<,.", b"A", options), b"\x01A");
+    assert_eq!(run("+.<<,", b"A", options), b"\x01");
+}
+
+#[test]
+fn debug_dump() {
+    // dumps around both ends of the tape, and from inside a loop
+    let source = "+++>-.#<<<<<++#[>+++<-#]>>>>>>>>>>#";
+
+    for width in WIDTHS {
+        let program =
+            Program::with_dialect("dump.bf", source, Dialect::DEBUG_DUMP);
+        let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+
+        let expected = interp::run(&ir, &b""[..], vec![], Default::default())
+            .unwrap()
+            .dumps;
+
+        for fuel in [None, Some(5), Some(u64::MAX)] {
+            let options = CompilerOptions {
+                fuel,
+                ..Default::default()
+            };
+            let binary = compile_with_options(ir.clone(), options).unwrap();
+            let output = create_and_run_bin_with_input(&binary, b"");
+
+            assert_eq!(output.stdout, [width.max() as u8]);
+            assert_eq!(output.stderr, expected);
+        }
+    }
+}
+
+#[test]
+fn embedded_input() {
+    // reads off the tape edge go through the wrapping path
+    let sources = [",[.,]!Hi", "<,.<<,.>>>,.>,.!AB", ",.,.!\n!"];
+
+    for source in sources {
+        for width in [CellWidth::U8, CellWidth::U16] {
+            let program =
+                Program::with_dialect("in.bf", source, Dialect::EMBEDDED_INPUT);
+            let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+            let eof = EofBehavior::Zero;
+            let expected = interp::run(&ir, &b"C"[..], vec![], eof).unwrap();
+
+            for fuel in [None, Some(0), Some(3), Some(u64::MAX)] {
+                let options = CompilerOptions { eof, fuel };
+                let binary = compile_with_options(ir.clone(), options).unwrap();
+                let output = create_and_run_bin_with_input(&binary, b"C");
+
+                assert_eq!(output.stdout, expected.output, "{source}");
+            }
+        }
+    }
+}
+
+#[test]
+fn procedures() {
+    // recursion, redefinition, and a procedure defined by another
+    let sources = [
+        "+(>.-[<:>]<)>+++++<:",
+        "+(>+.<):(>++.<):",
+        "++(-(>+.<)+):-:",
+        "+(>,.<)::!AB",
+    ];
+    let dialect = Dialect::PROCEDURES | Dialect::EMBEDDED_INPUT;
+
+    for source in sources {
+        for width in WIDTHS {
+            let program = Program::with_dialect("proc.b", source, dialect);
+            let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+            let eof = EofBehavior::Zero;
+            let expected = interp::run(&ir, &b""[..], vec![], eof).unwrap();
+
+            for fuel in [None, Some(0), Some(3), Some(u64::MAX)] {
+                let options = CompilerOptions { eof, fuel };
+                let binary = compile_with_options(ir.clone(), options).unwrap();
+                let output = create_and_run_bin_with_input(&binary, b"");
+
+                assert_eq!(output.stdout, expected.output, "{source}");
+                assert_eq!(output.status.code(), Some(0), "{source}");
+            }
+        }
+    }
+}
+
+#[test]
+fn procedure_errors() {
+    let cases = [
+        (
+            "+.:",
+            CellWidth::U8,
+            "a procedure is called before it's defined\n",
+        ),
+        (
+            "+(:):",
+            CellWidth::U8,
+            "more than 65536 procedure calls are in progress\n",
+        ),
+        (
+            "-(.)",
+            CellWidth::U16,
+            "can't define a procedure numbered 256 or above\n",
+        ),
+        (
+            "+(.)-:",
+            CellWidth::U64,
+            "a procedure is called before it's defined\n",
+        ),
+    ];
+
+    for (source, width, message) in cases {
+        let program =
+            Program::with_dialect("proc.b", source, Dialect::PROCEDURES);
+        let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+        let binary = compile_with_options(ir, Default::default()).unwrap();
+        let output = create_and_run_bin_with_input(&binary, b"");
+
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), message);
+        assert_eq!(output.status.code(), Some(1), "{source}");
+    }
+}
+
+#[test]
+fn fork() {
+    // processes run side by side, so only the combined output is fixed
+    let sources = [
+        "Y>++++++[<++++++++>-]<+.",
+        "YY>>++++++[<++++++++>-]<+.<.",
+        "+++++[>++++++++++<-]>.Y.>.",
+        "+(Y>+++[<++++++++++++++++>-]<.):",
+    ];
+    let dialect = Dialect::FORK | Dialect::PROCEDURES;
+
+    for source in sources {
+        for width in [CellWidth::U8, CellWidth::U64] {
+            let program = Program::with_dialect("fork.b", source, dialect);
+            let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+            let eof = EofBehavior::Zero;
+            let mut expected =
+                interp::run(&ir, &b""[..], vec![], eof).unwrap().output;
+            expected.sort();
+
+            for fuel in [None, Some(0), Some(3), Some(u64::MAX)] {
+                let options = CompilerOptions { eof, fuel };
+                let binary = compile_with_options(ir.clone(), options).unwrap();
+                let mut output = create_and_run_bin_with_input(&binary, b"");
+
+                output.stdout.sort();
+                assert_eq!(output.stdout, expected, "{source}");
+                assert_eq!(output.status.code(), Some(0), "{source}");
+            }
+        }
+    }
+}
+
+/// Sets the cells from the current one onwards to `cells`, then comes back.
+fn lay_out(cells: &[u64]) -> String {
+    let mut source = String::new();
+    for &cell in cells {
+        source += &"+".repeat(cell as usize);
+        source += ">";
+    }
+    source + &"<".repeat(cells.len())
+}
+
+#[test]
+fn syscalls() {
+    // write(1, "Hi", 2), then the number of bytes written
+    let hi = lay_out(&[1, 3, 0, 1, 1, 1, 2, b'H'.into(), b'i'.into(), 0, 1, 2]);
+    // write(1, &cell[12], 1), with fd 1 spelled out over two cells
+    let a = lay_out(&[1, 3, 0, 2, 0, 1, 2, 1, 12, 0, 1, 1, b'A'.into()]);
+    // write(99, ...) fails with -EBADF
+    let bad_fd = lay_out(&[1, 3, 0, 1, 99, 1, 0, 0, 1, 0]);
+    // read(0, &cell[7], 3) fills in the cells, and read(0, &cell[20], 1)
+    let read = lay_out(&[0, 3, 0, 1, 0, 1, 3, 0, 0, 0, 0, 1, 3]);
+    let read_at = lay_out(&[0, 3, 0, 1, 0, 2, 1, 20, 0, 1, 1]);
+    let cases = [
+        (format!("{hi}%{}.", "+".repeat(48)), &b"Hi2"[..], true),
+        (format!("{a}%"), b"A", false),
+        (format!("{bad_fd}%."), &[-9i8 as u8], false),
+        (format!("{read}%>>>>>>>.>.>."), b"xyz", true),
+        (format!("{read_at}%{}.", ">".repeat(20)), b"x", false),
+    ];
+
+    for (source, expected, bytes_only) in cases {
+        let widths: &[CellWidth] = if bytes_only {
+            &[CellWidth::U8]
+        } else {
+            &WIDTHS
+        };
+        for &width in widths {
+            let program =
+                Program::with_dialect("sys.b", &source, Dialect::SYSCALLS);
+            let ir = optimize(IR::parse_with_width(&program, width).unwrap()).0;
+
+            for fuel in [None, Some(0), Some(u64::MAX)] {
+                let options = CompilerOptions {
+                    fuel,
+                    ..Default::default()
+                };
+                let binary = compile_with_options(ir.clone(), options).unwrap();
+                let output = create_and_run_bin_with_input(&binary, b"xyz");
+
+                assert_eq!(output.stdout, expected, "{source}");
+            }
+        }
+    }
+}
+
+#[test]
+fn syscall_errors() {
+    let cases = [
+        (lay_out(&[39, 7]), "a syscall takes at most 6 arguments\n"),
+        (
+            lay_out(&[39, 1, 3, 0]),
+            "unknown kind of syscall argument\n",
+        ),
+        (
+            format!("<<<<<<{}", lay_out(&[1, 1, 1, 10])),
+            "syscall argument runs off the end of the tape\n",
+        ),
+    ];
+
+    for (source, message) in cases {
+        let program = Program::with_dialect(
+            "sys.b",
+            &format!("{source}%"),
+            Dialect::SYSCALLS,
+        );
+        let ir = optimize(IR::parse(&program).unwrap()).0;
+        let binary = compile_with_options(ir, Default::default()).unwrap();
+        let output = create_and_run_bin_with_input(&binary, b"");
+
+        assert_eq!(String::from_utf8(output.stderr).unwrap(), message);
+        assert_eq!(output.status.code(), Some(1), "{source}");
+    }
+}
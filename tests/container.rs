@@ -0,0 +1,68 @@
+#![cfg(feature = "serde")]
+
+use concussion::frontend::container::{
+    decode, encode, ContainerError, FORMAT_VERSION,
+};
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{CellWidth, Program, IR};
+use pretty_assertions::assert_eq;
+
+const SOURCE: &str = "++++++++[>++++++++<-]>+.\n<<[>>>+.<<<-[>]]";
+
+fn ir() -> IR {
+    let program = Program::new("test.bf", SOURCE);
+    optimize(IR::parse_with_width(&program, CellWidth::U16).unwrap()).0
+}
+
+#[test]
+fn ir_round_trip() {
+    let ir = ir();
+
+    let binary: IR = decode(&encode(&ir).unwrap()).unwrap();
+    assert_eq!(binary.instructions, ir.instructions);
+    assert_eq!(binary.cell_width, ir.cell_width);
+
+    let json = serde_json::to_string(&ir).unwrap();
+    let json: IR = serde_json::from_str(&json).unwrap();
+    assert_eq!(json.instructions, ir.instructions);
+    assert_eq!(json.cell_width, ir.cell_width);
+}
+
+#[test]
+fn program_round_trip() {
+    let program = Program::new("test.bf", SOURCE);
+    let decoded: Program = decode(&encode(&program).unwrap()).unwrap();
+
+    assert_eq!(decoded.name(), "test.bf");
+    assert_eq!(decoded.source(), SOURCE);
+    assert_eq!(
+        IR::parse(&decoded).unwrap().instructions,
+        IR::parse(&program).unwrap().instructions
+    );
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut bytes = encode(&ir()).unwrap();
+    bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+    let error = decode::<IR>(&bytes).err().unwrap();
+    assert!(matches!(
+        error,
+        ContainerError::UnsupportedVersion { found, expected }
+            if found == FORMAT_VERSION + 1 && expected == FORMAT_VERSION
+    ));
+}
+
+#[test]
+fn rejects_garbage() {
+    for bytes in [&b""[..], b"CNC", b"not a program at all"] {
+        let error = decode::<IR>(bytes).err().unwrap();
+        assert!(matches!(error, ContainerError::BadMagic), "{error}");
+    }
+
+    // a valid header with a truncated payload
+    let bytes = encode(&ir()).unwrap();
+    let error = decode::<IR>(&bytes[..bytes.len() / 2]).err().unwrap();
+    assert!(matches!(error, ContainerError::Payload(_)), "{error}");
+}
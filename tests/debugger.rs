@@ -0,0 +1,162 @@
+use std::io;
+
+use concussion::debugger::{DebugError, Debugger, Stop, StopReason};
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{Dialect, Program, IR};
+use pretty_assertions::assert_eq;
+
+const SOURCE: &str = "+++\n[>++<-]\n>[-]+.\n";
+
+fn position(stop: &Stop) -> Option<(usize, usize)> {
+    stop.location.as_ref().map(|l| (l.span.line, l.span.col))
+}
+
+#[test]
+fn breakpoints() {
+    let program = Program::from(SOURCE);
+    let ir = IR::parse(&program).unwrap();
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+    let (mut input, mut output) = (io::empty(), vec![]);
+
+    let pc = debugger.add_breakpoint(2, 2).unwrap();
+    assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [pc]);
+
+    for remaining in (0..3).rev() {
+        let stop = debugger.resume(&mut input, &mut output).unwrap();
+        assert_eq!(stop.reason, StopReason::Breakpoint);
+        assert_eq!(stop.pc, pc);
+        assert_eq!(position(&stop), Some((2, 2)));
+
+        let snapshot = debugger.snapshot();
+        assert_eq!(snapshot.pointer, 0);
+        assert_eq!(snapshot.window(1), [0, remaining + 1, 2 * (2 - remaining)]);
+    }
+
+    assert!(debugger.remove_breakpoint(pc));
+    let stop = debugger.resume(&mut input, &mut output).unwrap();
+    assert_eq!(stop.reason, StopReason::Finished);
+    assert_eq!(position(&stop), None);
+    assert_eq!(output, [1]);
+}
+
+#[test]
+fn stepping() {
+    let program = Program::from(SOURCE);
+    let ir = IR::parse(&program).unwrap();
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+    let (mut input, mut output) = (io::empty(), io::sink());
+
+    assert_eq!(debugger.location().unwrap().location(), "<source>:1:1");
+
+    let stop = debugger.step(&mut input, &mut output).unwrap();
+    assert_eq!(
+        (stop.reason, position(&stop)),
+        (StopReason::Step, Some((2, 1)))
+    );
+
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(position(&stop), Some((3, 1)));
+    assert_eq!(debugger.snapshot().window(1), [0, 0, 6]);
+
+    debugger.step(&mut input, &mut output).unwrap();
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(position(&stop), Some((3, 5)));
+    assert_eq!(debugger.snapshot().pointer, 1);
+
+    // outside of a loop it's the same as a step
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(position(&stop), Some((3, 6)));
+}
+
+#[test]
+fn watchpoints() {
+    let program = Program::from(SOURCE);
+    let ir = optimize(IR::parse(&program).unwrap()).0;
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+    let (mut input, mut output) = (io::empty(), io::sink());
+
+    debugger.watch(1).unwrap();
+
+    let mut changes = vec![];
+    loop {
+        let stop = debugger.resume(&mut input, &mut output).unwrap();
+        match stop.reason {
+            StopReason::Watchpoint { cell, old, new } => {
+                assert_eq!(cell, 1);
+                changes.push((old, new, position(&stop).unwrap()));
+            }
+            reason => {
+                assert_eq!(reason, StopReason::Finished);
+                break;
+            }
+        }
+    }
+
+    // the loop is folded into a multiply, and `[-]+` into a single set
+    assert_eq!(changes, [(0, 6, (2, 1)), (6, 1, (3, 6))]);
+}
+
+#[test]
+fn folded_positions() {
+    let program = Program::from(SOURCE);
+    let ir = optimize(IR::parse(&program).unwrap()).0;
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+
+    // `[-]+` is a single set, and the `>` before it is moved to the end
+    let set = debugger.add_breakpoint(3, 3).unwrap();
+    assert_eq!(debugger.add_breakpoint(3, 5).unwrap(), set);
+    assert_eq!(debugger.add_breakpoint(2, 4).unwrap(), 1);
+    assert_eq!(debugger.add_breakpoint(2, 8).unwrap(), 5);
+    assert_eq!(ir.instructions[set].span.col, 2);
+
+    assert!(matches!(
+        debugger.add_breakpoint(3, 7),
+        Err(DebugError::NoCode { line: 3, col: 7 })
+    ));
+    assert!(debugger.add_breakpoint(9, 1).is_err());
+    assert!(debugger.add_breakpoint(1, 0).is_err());
+    assert!(matches!(
+        debugger.watch(30_000),
+        Err(DebugError::NoSuchCell(_))
+    ));
+}
+
+#[test]
+fn stepping_over_calls() {
+    // procedure 1 counts the next cell down to zero, recursing each time
+    let source = "+(>-[<:>]<)\n>+++<:\n>.";
+    let program = Program::with_dialect("proc.b", source, Dialect::PROCEDURES);
+    let ir = IR::parse(&program).unwrap();
+    let (mut input, mut output) = (io::empty(), io::sink());
+
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+    debugger.add_breakpoint(2, 6).unwrap();
+    let stop = debugger.resume(&mut input, &mut output).unwrap();
+    assert_eq!(position(&stop), Some((2, 6)));
+
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(
+        (stop.reason, position(&stop)),
+        (StopReason::Step, Some((3, 1)))
+    );
+    assert_eq!(debugger.snapshot().window(1), [0, 1, 0]);
+    assert_eq!(debugger.machine().call_depth(), 0);
+
+    // breakpoints in the procedure still stop it, however deep
+    let mut debugger = Debugger::new(&program, &ir, Default::default());
+    let pc = debugger.add_breakpoint(1, 7).unwrap();
+    debugger.resume(&mut input, &mut output).unwrap();
+    assert_eq!(debugger.machine().call_depth(), 1);
+
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(
+        (stop.reason, position(&stop)),
+        (StopReason::Breakpoint, Some((1, 7)))
+    );
+    assert_eq!(debugger.machine().call_depth(), 2);
+
+    debugger.remove_breakpoint(pc);
+    let stop = debugger.step_over(&mut input, &mut output).unwrap();
+    assert_eq!(position(&stop), Some((1, 8)));
+    assert_eq!(debugger.machine().call_depth(), 2);
+}
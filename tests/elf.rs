@@ -1,17 +1,13 @@
 use concussion::backend::compiler::CompilerError;
 use concussion::test_helpers::create_and_run_bin;
 use pretty_assertions::assert_eq;
-use std::collections::HashMap;
 use std::result::Result;
 
 use concussion::backend::elf::{
     compile_to_elf, LabelMap, PhdrFlags, Segment, SegmentBuilder,
 };
-use concussion::{backend, segment};
-use iced_x86::{
-    code_asm::{self, CodeAssembler},
-    IcedError,
-};
+use concussion::segment;
+use iced_x86::code_asm::{self, CodeAssembler};
 
 #[test]
 fn hello_world() {
@@ -0,0 +1,193 @@
+use std::io::{self, Write};
+
+use concussion::backend::compiler::{
+    compile_with_options, CompilerOptions, EofBehavior,
+};
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{
+    CellWidth, Dialect, Instruction, Program, CALL_DEPTH_LIMIT, IR, TAPE_LENGTH,
+};
+use concussion::interp::{dump, run, InterpError, Machine, Status};
+use concussion::test_helpers::create_and_run_bin_with_input;
+use pretty_assertions::assert_eq;
+
+fn parse(source: &str, width: CellWidth) -> IR {
+    optimize(IR::parse_with_width(&source.into(), width).unwrap()).0
+}
+
+#[test]
+fn matches_compiled_code() {
+    let sources = [
+        ",.,.,.,.",
+        "+++++,.",
+        "++++++++[>++++++++<-]>+.<<+<++>>>.<<<[>>>+.<<<-]>>[<+>-]<.",
+        "+>>++++++++[<++++++++++++++++++++++++++++++++>-]<<[>]<<.>.",
+        "-[>+<-----]>.,[>+<-]>.",
+    ];
+    let eofs = [
+        EofBehavior::Unchanged,
+        EofBehavior::Zero,
+        EofBehavior::MaxValue,
+    ];
+
+    for source in sources {
+        for width in [CellWidth::U8, CellWidth::U16] {
+            for eof in eofs {
+                let ir = parse(source, width);
+                let mut stdout = vec![];
+                let execution = run(&ir, &b"hi"[..], &mut stdout, eof).unwrap();
+
+                let options = CompilerOptions {
+                    eof,
+                    ..Default::default()
+                };
+                let binary = compile_with_options(ir, options).unwrap();
+                let expected = create_and_run_bin_with_input(&binary, b"hi");
+
+                assert_eq!(execution.output, expected.stdout, "{source}");
+                assert_eq!(stdout, expected.stdout, "{source}");
+            }
+        }
+    }
+}
+
+#[test]
+fn final_state() {
+    let ir = parse("+++[>++<-]>>-<<<+", CellWidth::U16);
+    let execution =
+        run(&ir, io::empty(), io::sink(), Default::default()).unwrap();
+
+    assert_eq!(execution.pointer, 29_999);
+    assert_eq!(execution.tape[..3], [0, 6, 65_535]);
+    assert_eq!(execution.tape[29_999], 1);
+    assert_eq!(execution.output, b"");
+}
+
+#[test]
+fn stepping() {
+    let ir = parse("+[>]", CellWidth::U8);
+    let mut machine = Machine::new(&ir, Default::default());
+
+    // the scan moves one cell per step
+    let mut steps = 0;
+    while machine.step(&mut io::empty(), &mut io::sink()).unwrap()
+        == Status::Running
+    {
+        steps += 1;
+    }
+
+    assert_eq!(steps, 2);
+    assert_eq!(machine.pointer(), 1);
+    assert_eq!(machine.pc(), ir.instructions.len());
+}
+
+#[test]
+fn write_errors() {
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let ir = parse("+.", CellWidth::U8);
+    let error = run(&ir, io::empty(), Broken, Default::default()).err();
+
+    assert!(matches!(error, Some(InterpError::Write(_))));
+}
+
+#[test]
+fn procedures() {
+    // procedure 1 calls itself until the call stack runs out
+    let program =
+        Program::with_dialect("proc.b", "+(>+<:):", Dialect::PROCEDURES);
+    let ir = IR::parse_with_width(&program, CellWidth::U64).unwrap();
+    let mut machine = Machine::new(&ir, Default::default());
+    let error = machine.run(&mut io::empty(), &mut io::sink()).err();
+
+    assert!(matches!(error, Some(InterpError::CallDepth)));
+    assert_eq!(machine.call_depth(), CALL_DEPTH_LIMIT);
+    assert_eq!(machine.next_instruction(), Some(Instruction::CallProc));
+    assert_eq!(machine.tape()[1], CALL_DEPTH_LIMIT as u64);
+
+    let program = Program::with_dialect("proc.b", "-(.)", Dialect::PROCEDURES);
+    let ir = IR::parse_with_width(&program, CellWidth::U16).unwrap();
+    let error = run(&ir, io::empty(), io::sink(), Default::default()).err();
+    assert!(matches!(error, Some(InterpError::ProcedureNumber(0xffff))));
+
+    let program =
+        Program::with_dialect("proc.b", "+(.)++:", Dialect::PROCEDURES);
+    let ir = IR::parse(&program).unwrap();
+    let error = run(&ir, io::empty(), io::sink(), Default::default()).err();
+    assert!(matches!(error, Some(InterpError::UndefinedProcedure(3))));
+}
+
+#[test]
+fn fork() {
+    let program = Program::with_dialect("fork.b", "+Y+.", Dialect::FORK);
+    let ir = IR::parse(&program).unwrap();
+    let mut machine = Machine::new(&ir, Default::default());
+    machine.step(&mut io::empty(), &mut io::sink()).unwrap();
+    machine.step(&mut io::empty(), &mut io::sink()).unwrap();
+
+    assert_eq!(machine.tape()[..2], [0, 0]);
+    let [child] = machine.forks() else {
+        panic!("expected one fork");
+    };
+    assert_eq!(child.pointer(), 1);
+    assert_eq!(child.tape()[..2], [1, 1]);
+    assert_eq!(
+        child.next_instruction(),
+        Some(Instruction::Add {
+            amount: 1,
+            offset: 0
+        })
+    );
+
+    // the child's output comes after its parent's
+    let execution = run(&ir, io::empty(), vec![], Default::default()).unwrap();
+    assert_eq!(execution.output, [1, 2]);
+    assert_eq!(execution.pointer, 0);
+}
+
+#[test]
+fn debug_dumps() {
+    // a forked process's dumps come after its parent's, like its output
+    let source = "+>++#<Y[#-]";
+    let program = Program::with_dialect(
+        "dump.bf",
+        source,
+        Dialect::DEBUG_DUMP | Dialect::FORK,
+    );
+    let ir = IR::parse(&program).unwrap();
+    let execution = run(&ir, io::empty(), vec![], Default::default()).unwrap();
+
+    let tape = |cells: &[u64]| {
+        let mut tape = vec![0; TAPE_LENGTH as usize];
+        tape[..cells.len()].copy_from_slice(cells);
+        tape
+    };
+    let expected = dump(&tape(&[1, 2]), 1, CellWidth::U8)
+        + &dump(&tape(&[1, 1]), 1, CellWidth::U8);
+    assert_eq!(String::from_utf8(execution.dumps).unwrap(), expected);
+    assert_eq!(execution.output, b"");
+}
+
+#[test]
+fn syscalls() {
+    // getpid() would be made from the test process, so it isn't made at all
+    let source = "+".repeat(39) + ".%";
+    let program = Program::with_dialect("sys.b", &source, Dialect::SYSCALLS);
+    let ir = IR::parse(&program).unwrap();
+
+    let mut machine = Machine::new(&ir, Default::default());
+    let error = machine.run(&mut io::empty(), &mut io::sink()).err();
+    assert!(matches!(error, Some(InterpError::Syscall)));
+    assert_eq!(machine.output(), b"'");
+    assert_eq!(machine.tape()[0], 39);
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use concussion::frontend::macros::{expand, MacroError, EXPANSION_LIMIT};
+use concussion::frontend::parser::{Dialect, ParseError, IR};
+use concussion::interp::run;
+use pretty_assertions::assert_eq;
+
+fn commands(source: &str) -> String {
+    let program = expand("m.bf", source, Dialect::empty()).unwrap();
+    program.commands().iter().map(|c| c.char()).collect()
+}
+
+fn error(source: &str) -> String {
+    let Err(MacroError::Syntax(diagnostic)) =
+        expand("m.bf", source, Dialect::empty())
+    else {
+        panic!("expected {source:?} not to expand");
+    };
+    diagnostic.to_string()
+}
+
+#[test]
+fn expands_to_plain_commands() {
+    let source = "\
+@def move(n) [->*$n+<*$n]
+@def print(c) +*$c.[-]
+@print(72) @move(2)
+";
+    let plain = format!("{}.[-][->>+<<]", "+".repeat(72));
+    assert_eq!(commands(source), plain);
+
+    // no parameters, an empty count and comments
+    assert_eq!(
+        commands("@def zero [-]\n@zero @zero() +*0 -* @ $x"),
+        "[-][-]-"
+    );
+    assert_eq!(commands("+*3>*2-*0[-]"), "+++>>[-]");
+
+    let program = expand("m.bf", "+*65.", Dialect::empty()).unwrap();
+    let ir = IR::parse(&program).unwrap();
+    let execution = run(&ir, io::empty(), vec![], Default::default()).unwrap();
+    assert_eq!(execution.output, b"A");
+}
+
+#[test]
+fn arguments_expand_where_called() {
+    // `$x` in the argument is `wrap`'s parameter, not `twice`'s
+    let source = "\
+@def twice(x) $x$x
+@def wrap(x, n) @twice($x+*$n)
+@wrap(>, 2)
+";
+    assert_eq!(commands(source), ">++>++");
+
+    // passing a parameter on keeps it usable as a count
+    let source = "@def rep(n) +*$n\n@def outer(m) @rep($m)\n@outer(3)";
+    assert_eq!(commands(source), "+++");
+
+    // arguments nest on parentheses, and a call can be repeated
+    let source = "@def id(x) $x\n@def pair(a, b) $a$b\n@pair(@id(+), -)*2";
+    assert_eq!(commands(source), "+-+-");
+}
+
+#[test]
+fn spans_point_at_definition_and_call() {
+    let source = "@def inc(n) +*$n\n>@inc(2)";
+    let program = expand("m.bf", source, Dialect::empty()).unwrap();
+    let ir = IR::parse(&program).unwrap();
+
+    let add = ir.instructions[1].span;
+    assert_eq!((add.line, add.col), (1, 13));
+    assert_eq!(program.expansions()[0].name, "inc");
+    assert_eq!(
+        program.snippet(add).to_string(),
+        "@def inc(n) +*$n\n            ^\nexpanded from `@inc` at m.bf:2:2\n\
+         >@inc(2)\n ^^^^^^^"
+    );
+
+    // a parse error inside a macro shows where it was called from too
+    let source = "@def open [+\n@def twice @open@open\n@twice";
+    let program = expand("m.bf", source, Dialect::empty()).unwrap();
+    let Err(ParseError::NestingErrors(diagnostics)) = IR::parse(&program)
+    else {
+        panic!("expected nesting errors");
+    };
+    assert_eq!(
+        diagnostics[0].snippet.to_string(),
+        "@def open [+\n          ^\n\
+         expanded from `@open` at m.bf:2:12\n@def twice @open@open\n           \
+         ^^^^^\nexpanded from `@twice` at m.bf:3:1\n@twice\n^^^^^^"
+    );
+}
+
+#[test]
+fn long_lines_expand_in_linear_time() {
+    // counting each column from the start of the line, for commands and
+    // repetitions alike, took seconds here
+    let line = "@def step +>\n".to_owned() + &"+>*1@step".repeat(100_000);
+    let start = Instant::now();
+    let program = expand("m.bf", &line, Dialect::empty()).unwrap();
+    let elapsed = start.elapsed();
+
+    assert_eq!(program.commands().len(), 400_000);
+    assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+
+    // the columns are still right at the far end of the line
+    let ir = IR::parse(&program).unwrap();
+    let last = ir.instructions.last().unwrap().span;
+    assert_eq!((last.line, last.col), (1, 12));
+    let Err(MacroError::Syntax(diagnostic)) =
+        expand("m.bf", &(line + "@nope"), Dialect::empty())
+    else {
+        panic!("expected `@nope` not to expand");
+    };
+    assert_eq!(diagnostic.snippet.location(), "m.bf:2:900001");
+}
+
+#[test]
+fn embedded_input_is_left_alone() {
+    let source = "@def echo ,.\n@echo*2!@hi";
+    let program = expand("m.bf", source, Dialect::EMBEDDED_INPUT).unwrap();
+    assert_eq!(program.input(), b"@hi");
+
+    let ir = IR::parse(&program).unwrap();
+    let execution = run(&ir, io::empty(), vec![], Default::default()).unwrap();
+    assert_eq!(execution.output, b"@h");
+}
+
+#[test]
+fn expansion_errors() {
+    let cases = [
+        ("@nope", "m.bf:1:1: there's no macro called `@nope`"),
+        (
+            "@def a +\n@def a -",
+            "m.bf:2:6: `@a` is already defined on line 1",
+        ),
+        ("@def a(x, x) $x", "m.bf:1:11: `x` is already a parameter"),
+        ("@def a(1x) +", "m.bf:1:8: `1x` isn't a parameter name"),
+        ("@def a(x +", "m.bf:1:7: the parameter list never ends"),
+        (
+            "@def a(x) $x\n@a(+",
+            "m.bf:2:3: the argument list never ends",
+        ),
+        (
+            "@def a(x) $x\n@a",
+            "m.bf:2:1: `@a` takes 1 argument, but 0 were given",
+        ),
+        (
+            "@def a(x) $y\n@a(+)",
+            "m.bf:1:11: `$y` isn't a parameter of `@a`",
+        ),
+        (
+            "@def a(x) +*$x\n@a(+)",
+            "m.bf:1:13: `$x` has to be a number to repeat by it",
+        ),
+        ("@def a @a\n@a", "m.bf:1:8: `@a` can't call itself"),
+        (
+            "@def a @b\n@def b +\n@a",
+            "m.bf:1:8: `@b` is defined after `@a`, on line 2",
+        ),
+        (
+            "@def a @def b +\n@a",
+            "m.bf:1:8: macros can't be defined inside another macro",
+        ),
+        ("@def", "m.bf:1:1: `@def` needs a name for the macro"),
+    ];
+    for (source, expected) in cases {
+        let message = error(source);
+        let first = message.lines().next().unwrap();
+        assert_eq!(first, expected, "for {source:?}");
+    }
+
+    let too_long = format!("+*{}", EXPANSION_LIMIT + 1);
+    assert!(error(&too_long).contains("expands to more than"));
+    assert!(
+        error("@def a ++\n@def b @a*1024\n@b*1024").contains(&format!(
+            "this expands to more than {EXPANSION_LIMIT} commands"
+        ))
+    );
+    assert!(error("+*99999999999999999999999").contains("expands to more"));
+
+    // each level calls the one before twice, without ever making a command
+    let mut nested = String::from("@def m0\n");
+    for level in 1..40 {
+        let prev = level - 1;
+        nested += &format!("@def m{level} @m{prev}@m{prev}\n");
+    }
+    nested += "@m39";
+    assert!(error(&nested).contains(&format!(
+        "this makes more than {EXPANSION_LIMIT} macro calls"
+    )));
+}
@@ -0,0 +1,134 @@
+use concussion::frontend::optimizer::{normalize, optimize};
+use concussion::frontend::parser::{Instruction, IR};
+use pretty_assertions::assert_eq;
+use proptest::prelude::*;
+
+const TAPE_LENGTH: usize = 30_000;
+
+#[derive(Debug, PartialEq, Eq)]
+struct State {
+    tape: Vec<u8>,
+    pointer: usize,
+    output: Vec<u8>,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            tape: vec![0; TAPE_LENGTH],
+            pointer: 0,
+            output: vec![],
+        }
+    }
+
+    fn cell(&mut self, offset: i64) -> &mut u8 {
+        let index = (self.pointer as i64 + offset)
+            .rem_euclid(TAPE_LENGTH as i64) as usize;
+        &mut self.tape[index]
+    }
+
+    fn shift(&mut self, by: i64) {
+        self.pointer =
+            (self.pointer as i64 + by).rem_euclid(TAPE_LENGTH as i64) as usize;
+    }
+}
+
+/// Runs straight-line Brainfuck one character at a time.
+fn reference(source: &str) -> State {
+    let mut state = State::new();
+    for c in source.chars() {
+        match c {
+            '+' => *state.cell(0) = state.cell(0).wrapping_add(1),
+            '-' => *state.cell(0) = state.cell(0).wrapping_sub(1),
+            '>' => state.shift(1),
+            '<' => state.shift(-1),
+            '.' => {
+                let value = *state.cell(0);
+                state.output.push(value);
+            }
+            _ => (),
+        }
+    }
+
+    state
+}
+
+fn evaluate(ir: &IR) -> State {
+    use Instruction as I;
+
+    let mut state = State::new();
+    for instr in &ir.instructions {
+        match instr.inner {
+            I::ShiftLeft(n) => state.shift(-(n as i64)),
+            I::ShiftRight(n) => state.shift(n as i64),
+            I::Add { amount, offset } => {
+                *state.cell(offset) =
+                    state.cell(offset).wrapping_add(amount as u8)
+            }
+            I::Sub { amount, offset } => {
+                *state.cell(offset) =
+                    state.cell(offset).wrapping_sub(amount as u8)
+            }
+            I::Write { offset } => {
+                let value = *state.cell(offset);
+                state.output.push(value);
+            }
+            other => panic!("not straight-line code: {other:?}"),
+        }
+    }
+
+    state
+}
+
+fn straight_line() -> impl Strategy<Value = String> {
+    // long runs of the same character, so counts past 256 come up
+    let run = (
+        prop::sample::select(vec!['+', '-', '<', '>', '.']),
+        1..300usize,
+    )
+        .prop_map(|(c, n)| c.to_string().repeat(n));
+    prop::collection::vec(prop_oneof![run, "[-+<>.]{1,8}"], 0..20)
+        .prop_map(|parts| parts.concat())
+}
+
+proptest! {
+    #[test]
+    fn parse_matches_reference(source in straight_line()) {
+        let ir = IR::parse(&source.as_str().into()).unwrap();
+        prop_assert_eq!(evaluate(&ir), reference(&source));
+    }
+
+    #[test]
+    fn optimize_matches_reference(source in straight_line()) {
+        let ir = optimize(IR::parse(&source.as_str().into()).unwrap()).0;
+        prop_assert_eq!(evaluate(&ir), reference(&source));
+    }
+
+    #[test]
+    fn parse_is_normalized(source in straight_line()) {
+        let ir = IR::parse(&source.as_str().into()).unwrap();
+        let before: Vec<_> = ir.instructions.iter().map(|i| i.inner).collect();
+        let after: Vec<_> =
+            normalize(ir).instructions.iter().map(|i| i.inner).collect();
+        prop_assert_eq!(before, after);
+    }
+}
+
+#[test]
+fn cancelled_runs_disappear() {
+    use Instruction as I;
+
+    let ir = IR::parse(&"+-+-><<>.+><-".into()).unwrap();
+    let instrs: Vec<_> = ir.instructions.iter().map(|i| i.inner).collect();
+    assert_eq!(instrs, [I::Write { offset: 0 }]);
+
+    let ir = IR::parse(&"+".repeat(256 + 3).as_str().into()).unwrap();
+    let instrs: Vec<_> = ir.instructions.iter().map(|i| i.inner).collect();
+    assert_eq!(
+        instrs,
+        [I::Add {
+            amount: 3,
+            offset: 0
+        }]
+    );
+}
@@ -0,0 +1,255 @@
+use concussion::backend::compiler::compile;
+use concussion::frontend::optimizer::{
+    defer_movement, fold_clear_loops, fold_mul_loops, fold_scan_loops,
+    optimize, remove_dead_loops, DeadLoops,
+};
+use concussion::frontend::parser::{
+    CellWidth, Dialect, Instruction, Program, IR,
+};
+use concussion::test_helpers::create_and_run_bin;
+use pretty_assertions::assert_eq;
+
+fn instructions(ir: &IR) -> Vec<Instruction> {
+    ir.instructions.iter().map(|i| i.inner).collect()
+}
+
+fn parse(source: &str) -> IR {
+    IR::parse(&source.into()).unwrap()
+}
+
+fn add(amount: u64, offset: i64) -> Instruction {
+    Instruction::Add { amount, offset }
+}
+
+fn sub(amount: u64, offset: i64) -> Instruction {
+    Instruction::Sub { amount, offset }
+}
+
+fn set(value: u64, offset: i64) -> Instruction {
+    Instruction::Set { value, offset }
+}
+
+fn mul_add(offset: i64, factor: u64) -> Instruction {
+    Instruction::MulAdd { offset, factor }
+}
+
+#[test]
+fn clear_loops() {
+    use Instruction as I;
+
+    let ir = fold_clear_loops(parse(">+++[-]+++<[+]--[>]"));
+    assert_eq!(
+        instructions(&ir),
+        [
+            I::ShiftRight(1),
+            set(3, 0),
+            I::ShiftLeft(1),
+            set(254, 0),
+            I::JumpForward(6),
+            I::ShiftRight(1),
+            I::JumpBackward(4),
+        ]
+    );
+}
+
+#[test]
+fn clear_loops_run() {
+    // prints 'A' once the loop clearing the 200 is out of the way
+    let source =
+        "++++++++++[>++++++++++++++++++++<-]>[-]+++++++++++++[<+++++>-]<.";
+    let binary = compile(optimize(parse(source)).0).unwrap();
+
+    assert_eq!(create_and_run_bin(&binary).stdout, b"A");
+}
+
+#[test]
+fn mul_loops() {
+    use Instruction as I;
+
+    let ir = fold_clear_loops(fold_mul_loops(parse(
+        "+++[->+>+++<<]>[-<++>]<<[->>+<<+]",
+    )));
+    assert_eq!(
+        instructions(&ir),
+        [
+            add(3, 0),
+            mul_add(1, 1),
+            mul_add(2, 3),
+            set(0, 0),
+            I::ShiftRight(1),
+            mul_add(-1, 2),
+            set(0, 0),
+            I::ShiftLeft(2),
+            // doesn't decrement its own cell, so it stays a loop
+            I::JumpForward(14),
+            sub(1, 0),
+            I::ShiftRight(2),
+            add(1, 0),
+            I::ShiftLeft(2),
+            add(1, 0),
+            I::JumpBackward(8),
+        ]
+    );
+}
+
+#[test]
+fn mul_loops_run() {
+    // 7 * 9 + 2 = 'A', also copied across the left edge of the tape
+    let source = "+++++++[->+++++++++<<+>]>++.<<[->>>+<<<]>>>.";
+    let binary = compile(optimize(parse(source)).0).unwrap();
+
+    assert_eq!(create_and_run_bin(&binary).stdout, b"A\x07");
+}
+
+#[test]
+fn scan_loops() {
+    use Instruction as I;
+
+    let ir = fold_scan_loops(parse("+[>]<[<<<]"));
+    assert_eq!(
+        instructions(&ir),
+        [
+            add(1, 0),
+            I::Scan { stride: 1 },
+            I::ShiftLeft(1),
+            I::Scan { stride: -3 },
+        ]
+    );
+}
+
+#[test]
+fn scan_loops_run() {
+    // cells 0 to 39 hold 1 to 40, so scanning crosses a few 16 byte chunks
+    let mut source: String = (1..=40).map(|n| "+".repeat(n) + ">").collect();
+    source += &"<".repeat(40);
+    // right to cell 40 and print 39, then left past the start of the tape
+    source += "[>]<.[<]>.";
+    // scans that wrap around the end of the tape in both directions
+    source += "[-]<++[>]<.>+>[<]>.>.";
+    // a wider stride, well away from everything else
+    source += &">".repeat(50);
+    source += "+>>>+>>>+<<<<<<[>>>]<<<.>>>.";
+
+    let binary = compile(optimize(parse(&source)).0).unwrap();
+
+    assert_eq!(create_and_run_bin(&binary).stdout, [40, 1, 2, 2, 1, 1, 0]);
+}
+
+#[test]
+fn deferred_movement() {
+    use Instruction as I;
+
+    let ir = defer_movement(parse(">+>++<.<<-[>>,<]><"));
+    assert_eq!(
+        instructions(&ir),
+        [
+            add(1, 1),
+            add(2, 2),
+            I::Write { offset: 1 },
+            sub(1, -1),
+            I::ShiftLeft(1),
+            I::JumpForward(8),
+            I::Read { offset: 2 },
+            I::ShiftRight(1),
+            I::JumpBackward(5),
+        ]
+    );
+}
+
+#[test]
+fn deferred_movement_run() {
+    // touches cells on both sides of the edges of the tape, so the wrapping
+    // path has to be taken as well as the direct one
+    let source = "++++++++[>++++++++<-]>+.<<+<++>>>.<<<[>>>+.<<<-]>>[<+>-]<.";
+    let binary = compile(optimize(parse(source)).0).unwrap();
+
+    assert_eq!(create_and_run_bin(&binary).stdout, b"AABC\x01");
+}
+
+#[test]
+fn wide_cells() {
+    let program = format!("{}[-]--[-->+<]", "-".repeat(300)).as_str().into();
+    let ir = IR::parse_with_width(&program, CellWidth::U16).unwrap();
+    assert_eq!(instructions(&ir)[0], sub(300, 0));
+
+    // the loop cell goes down by two, so it isn't a multiply loop
+    let ir = fold_mul_loops(fold_clear_loops(ir));
+    assert_eq!(
+        instructions(&ir)[..2],
+        [set(65534, 0), Instruction::JumpForward(6)]
+    );
+}
+
+#[test]
+fn dead_loops() {
+    use Instruction as I;
+
+    let (ir, removed) = remove_dead_loops(parse("[a[b]c.]++[->+<][-]>>[<]."));
+    assert_eq!(
+        instructions(&ir),
+        [
+            add(2, 0),
+            I::JumpForward(6),
+            sub(1, 0),
+            I::ShiftRight(1),
+            add(1, 0),
+            I::ShiftLeft(1),
+            I::JumpBackward(1),
+            I::ShiftRight(2),
+            I::JumpForward(10),
+            I::ShiftLeft(1),
+            I::JumpBackward(8),
+            I::Write { offset: 0 },
+        ]
+    );
+    assert_eq!(
+        removed,
+        DeadLoops {
+            loops: 3,
+            instructions: 8,
+        }
+    );
+}
+
+#[test]
+fn dead_loops_around_procedures() {
+    use Instruction as I;
+
+    // the body of a procedure can be run with any tape, but defining it
+    // doesn't change what's known, and calling it forgets everything
+    let program =
+        Program::with_dialect("proc.b", "([-])[-]:[-]", Dialect::PROCEDURES);
+    let (ir, removed) = remove_dead_loops(IR::parse(&program).unwrap());
+    assert_eq!(
+        instructions(&ir),
+        [
+            I::DefineProc(4),
+            I::JumpForward(3),
+            sub(1, 0),
+            I::JumpBackward(1),
+            I::EndProc(0),
+            I::CallProc,
+            I::JumpForward(8),
+            sub(1, 0),
+            I::JumpBackward(6),
+        ]
+    );
+    assert_eq!(removed.loops, 1);
+}
+
+#[test]
+fn dead_loops_run() {
+    // the comment loops would print and never stop if they ran
+    let source = format!("[.+]{}.[-][.+]", "+".repeat(65));
+    let (ir, removed) = optimize(parse(&source));
+    assert_eq!(
+        removed,
+        DeadLoops {
+            loops: 2,
+            instructions: 8,
+        }
+    );
+    let binary = compile(ir).unwrap();
+
+    assert_eq!(create_and_run_bin(&binary).stdout, b"A");
+}
@@ -0,0 +1,196 @@
+use concussion::frontend::parser::{
+    Dialect, Instruction, ParseError, Program, IR,
+};
+use concussion::frontend::span::Span;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn instruction_spans() {
+    let program = Program::new("spans.bf", "++ +\n  >>.");
+    let ir = IR::parse(&program).unwrap();
+
+    let spans: Vec<_> = ir.instructions.iter().map(|i| i.span).collect();
+    assert_eq!(
+        spans,
+        [
+            Span {
+                offset: 0,
+                len: 4,
+                line: 1,
+                col: 1,
+                expansion: None,
+            },
+            Span {
+                offset: 7,
+                len: 2,
+                line: 2,
+                col: 3,
+                expansion: None,
+            },
+            Span {
+                offset: 9,
+                len: 1,
+                line: 2,
+                col: 5,
+                expansion: None,
+            },
+        ]
+    );
+    assert_eq!(
+        ir.instructions[0].inner,
+        Instruction::Add {
+            amount: 3,
+            offset: 0
+        }
+    );
+}
+
+#[test]
+fn unmatched_bracket_location() {
+    let program = Program::new("prog.bf", "+++\n  +[>+<-\n");
+    let err = IR::parse(&program).err().unwrap();
+
+    assert_eq!(
+        err.to_string(),
+        "prog.bf:2:4: missing matching brace for `[`\n  +[>+<-\n   ^\n\
+         hint: did you mean to close the loop opened at line 2 at the end of \
+         the program?"
+    );
+}
+
+#[test]
+fn reports_every_unmatched_bracket() {
+    let program = Program::new("prog.bf", "]\n+[\n-\n");
+    let Err(ParseError::NestingErrors(diagnostics)) = IR::parse(&program)
+    else {
+        panic!("expected nesting errors");
+    };
+
+    let locations: Vec<_> =
+        diagnostics.iter().map(|d| d.snippet.location()).collect();
+    assert_eq!(locations, ["prog.bf:1:1", "prog.bf:2:2"]);
+}
+
+#[test]
+fn hint_from_indentation() {
+    let source = "[\n  +[\n  -\n]\n";
+    let Err(ParseError::NestingErrors(diagnostics)) =
+        IR::parse(&Program::new("prog.bf", source))
+    else {
+        panic!("expected nesting errors");
+    };
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].hint.as_deref(),
+        Some(
+            "the `]` on line 4 is indented like this loop, but closes the \
+             one opened at line 2; did you mean to close the loop opened at \
+             line 2 before it?"
+        )
+    );
+}
+
+#[test]
+fn debug_dump_is_opt_in() {
+    let source = "+#>#";
+
+    let plain = IR::parse(&Program::new("dump.bf", source)).unwrap();
+    assert!(!plain
+        .instructions
+        .iter()
+        .any(|i| i.inner == Instruction::Debug));
+
+    let program = Program::with_dialect("dump.bf", source, Dialect::DEBUG_DUMP);
+    let ir = IR::parse(&program).unwrap();
+    let debug: Vec<_> = ir
+        .instructions
+        .iter()
+        .filter(|i| i.inner == Instruction::Debug)
+        .map(|i| i.span.col)
+        .collect();
+    assert_eq!(debug, [2, 4]);
+}
+
+#[test]
+fn embedded_input() {
+    let source = "+[,.]\n! input! with a !";
+
+    let program =
+        Program::with_dialect("in.bf", source, Dialect::EMBEDDED_INPUT);
+    assert_eq!(program.input(), b" input! with a !");
+    assert_eq!(program.source(), source);
+    let ir = IR::parse(&program).unwrap();
+    assert_eq!(ir.input, program.input());
+    assert_eq!(ir.instructions.len(), 5);
+
+    // otherwise `!` and everything after it are comments
+    let plain = Program::new("in.bf", source);
+    assert_eq!(plain.input(), b"");
+    assert_eq!(IR::parse(&plain).unwrap().instructions.len(), 5);
+    let plain = Program::new("in.bf", "!+");
+    assert_eq!(IR::parse(&plain).unwrap().instructions.len(), 1);
+}
+
+#[test]
+fn procedures() {
+    let source = "+(>[-]<):(";
+
+    let plain = IR::parse(&Program::new("proc.b", source)).unwrap();
+    assert_eq!(plain.instructions.len(), 6);
+
+    // a procedure can't end inside a loop, or a loop inside a procedure
+    let program = Program::with_dialect("proc.b", source, Dialect::PROCEDURES);
+    let Err(ParseError::NestingErrors(diagnostics)) = IR::parse(&program)
+    else {
+        panic!("expected nesting errors");
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "proc.b:1:10: missing matching brace for `(`\n+(>[-]<):(\n         ^\n\
+         hint: did you mean to end the procedure started at line 1 with a \
+         `)`?"
+    );
+
+    let program = Program::with_dialect("proc.b", "([)]", Dialect::PROCEDURES);
+    let Err(ParseError::NestingErrors(diagnostics)) = IR::parse(&program)
+    else {
+        panic!("expected nesting errors");
+    };
+    let locations: Vec<_> =
+        diagnostics.iter().map(|d| d.snippet.location()).collect();
+    assert_eq!(locations, ["proc.b:1:1", "proc.b:1:3"]);
+
+    let program =
+        Program::with_dialect("proc.b", "+(>[-]<):", Dialect::PROCEDURES);
+    let ir = IR::parse(&program).unwrap();
+    let instrs: Vec<_> = ir.instructions.iter().map(|i| i.inner).collect();
+    assert_eq!(
+        instrs[1..],
+        [
+            Instruction::DefineProc(7),
+            Instruction::ShiftRight(1),
+            Instruction::JumpForward(5),
+            Instruction::Sub {
+                amount: 1,
+                offset: 0
+            },
+            Instruction::JumpBackward(3),
+            Instruction::ShiftLeft(1),
+            Instruction::EndProc(1),
+            Instruction::CallProc,
+        ]
+    );
+}
+
+#[test]
+fn fork_is_opt_in() {
+    let plain = IR::parse(&Program::new("fork.b", "+Y.")).unwrap();
+    assert_eq!(plain.instructions.len(), 2);
+
+    let program = Program::with_dialect("fork.b", "+Y.", Dialect::FORK);
+    let ir = IR::parse(&program).unwrap();
+    assert_eq!(ir.instructions[1].inner, Instruction::Fork);
+    assert_eq!(ir.instructions[1].span.col, 2);
+}
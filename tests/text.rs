@@ -0,0 +1,162 @@
+use concussion::backend::compiler::compile;
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{
+    CellWidth, Dialect, Instruction, Program, IR,
+};
+use concussion::frontend::span::Spanned;
+use concussion::test_helpers::create_and_run_bin;
+use pretty_assertions::assert_eq;
+
+fn instructions(ir: &IR) -> Vec<Instruction> {
+    ir.instructions.iter().map(|i| i.inner).collect()
+}
+
+#[test]
+fn prints_ir() {
+    let program = "++++++++[>++++++++<-]>+.<<[>>>+.<<<-[>]]".into();
+    let ir = optimize(IR::parse(&program).unwrap()).0;
+
+    assert_eq!(
+        ir.to_string(),
+        "\
+cells u8
+add 8
+mul_add 8 @1
+set 0
+add 1 @1
+write @1
+left 1
+L0: loop L1
+    add 1 @3
+    write @3
+    sub 1
+    scan 1
+L1: end L0
+"
+    );
+}
+
+#[test]
+fn round_trip() {
+    let sources = [
+        "",
+        "+[-]>>,[.,]<<",
+        "++++++++[>++++++++<-]>+.<<+<++>>>.<<<[>>>+.<<<-]>>[<+>-]<.",
+        "[[[]][[]]]+[>[<]>>-]",
+        ",[.,]!Some input that wraps onto a second line",
+        "+(>[-(.)]<+:)>+<:",
+        "+Y[>Y.]",
+        "+>+++[>%<-]",
+    ];
+
+    for source in sources {
+        for width in [CellWidth::U8, CellWidth::U64] {
+            let program = Program::with_dialect(
+                "<source>",
+                source,
+                Dialect::EMBEDDED_INPUT
+                    | Dialect::PROCEDURES
+                    | Dialect::FORK
+                    | Dialect::SYSCALLS,
+            );
+            let parsed = IR::parse_with_width(&program, width).unwrap();
+
+            for ir in [optimize(parsed.clone()).0, parsed] {
+                let text = ir.to_string();
+                let back: IR = text.parse().unwrap();
+
+                assert_eq!(instructions(&back), instructions(&ir), "{text}");
+                assert_eq!(back.cell_width, width);
+                assert_eq!(back.input, ir.input);
+                assert_eq!(back.to_string(), text);
+            }
+        }
+    }
+}
+
+#[test]
+fn hand_written() {
+    let text = "
+        ; prints 'A', then copies 7 two cells over
+        set 65
+        write
+          start:   loop done   ; labels can be anything
+              sub 1
+          done: end start
+        add 7 @1
+        right 1
+        mul_add 1 @2
+        write @2
+        debug
+    ";
+    let ir: IR = text.parse().unwrap();
+
+    assert_eq!(instructions(&ir)[2], Instruction::JumpForward(4));
+    assert_eq!(ir.instructions[0].span.line, 3);
+
+    let binary = compile(ir).unwrap();
+    assert_eq!(create_and_run_bin(&binary).stdout, b"A\x07");
+}
+
+#[test]
+fn errors() {
+    let cases = [
+        (
+            "cells u8\nadd 300",
+            "<ir>:2:5: 300 doesn't fit in a u8 cell",
+        ),
+        ("add 1 @2 @3", "<ir>:1:10: unexpected argument"),
+        ("right", "<ir>:1:1: `right` is missing an argument"),
+        ("frob 2", "<ir>:1:1: unknown instruction `frob`"),
+        ("write 2", "<ir>:1:7: expected `@offset`"),
+        ("a: loop a", "<ir>:1:4: `loop` never ends"),
+        (
+            "a: loop b\nb: end c\nc: end a",
+            "<ir>:2:4: this `end` closes",
+        ),
+        (
+            "a: proc b\nb: end a",
+            "<ir>:2:4: `end` can't close the `proc` on line 1",
+        ),
+        (
+            "a: end_proc a",
+            "<ir>:1:4: `end_proc` without a `proc` to close",
+        ),
+        ("a: proc a\ncall", "<ir>:1:4: `proc` never ends"),
+        ("add 1\ncells u16", "<ir>:2:1: `cells` has to come before"),
+        ("input 1 256", "<ir>:1:9: expected a number"),
+        ("write\ninput 1", "<ir>:2:1: `input` has to come before"),
+    ];
+
+    for (text, expected) in cases {
+        let error = text.parse::<IR>().err().unwrap().to_string();
+        assert!(error.starts_with(expected), "{error}");
+    }
+}
+
+#[test]
+fn malformed_ir() {
+    use Instruction as I;
+
+    let ir = |instructions: Vec<Instruction>| IR {
+        instructions: instructions
+            .into_iter()
+            .map(|i| Spanned::new(i, Default::default()))
+            .collect(),
+        cell_width: CellWidth::U8,
+        input: vec![],
+    };
+
+    // an `end` with nothing to close doesn't indent anything
+    let unopened = ir(vec![I::JumpBackward(0), I::Write { offset: 0 }]);
+    assert_eq!(unopened.to_string(), "cells u8\nL0: end L0\nwrite\n");
+
+    // a jump to something that isn't one has no label to name, so it gets
+    // the raw index instead, which reading the text back rejects
+    for target in [1, u64::MAX] {
+        let ir = ir(vec![I::JumpForward(target), I::Write { offset: 0 }]);
+        let text = ir.to_string();
+        assert_eq!(text, format!("cells u8\nL0: loop @{target}\n    write\n"));
+        assert!(text.parse::<IR>().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::io;
+
+use concussion::backend::compiler::compile;
+use concussion::frontend::optimizer::optimize;
+use concussion::frontend::parser::{Command, Program, IR};
+use concussion::frontend::tokens::{RenderError, TableError, TokenTable};
+use concussion::interp;
+use concussion::test_helpers::create_and_run_bin;
+use pretty_assertions::assert_eq;
+
+const SOURCES: [&str; 3] = [
+    "++++++++[>++++++++<-]>+.<<+<++>>>.<<<[>>>+.<<<-]>>[<+>-]<.",
+    "+>>++++++++[<++++++++++++++++++++++++++++++++>-]<<[>]<<.>.",
+    "-[>+<-----]>.[-]+++[>+>++<<-]>>[<]>.",
+];
+
+fn custom() -> TokenTable {
+    TokenTable::new([
+        (Command::Movr, "right"),
+        (Command::Movl, "left"),
+        (Command::Incr, "more"),
+        (Command::Incr, "+"),
+        (Command::Decr, "less"),
+        (Command::Writ, "say"),
+        (Command::Read, "hear"),
+        (Command::JmpF, "while"),
+        (Command::JmpB, "end while"),
+    ])
+    .unwrap()
+}
+
+fn output(ir: &IR) -> Vec<u8> {
+    interp::run(ir, io::empty(), io::sink(), Default::default())
+        .unwrap()
+        .output
+}
+
+#[test]
+fn ook() {
+    // the `<` is split over two lines
+    let source = "Ook. Ook. Ook. Ook. Ook! Ook?\nOok. Ook? Ook. Ook.\tOok?\n\
+                  Ook. Ook! Ook! Ook? Ook! Ook. Ook?\nOok! Ook.";
+    let program = TokenTable::ook().tokenize("hi.ook", source);
+
+    assert_eq!(
+        TokenTable::brainfuck().render_program(&program).unwrap(),
+        "++[>+<-]>.\n"
+    );
+    let spans: Vec<_> = IR::parse(&program)
+        .unwrap()
+        .instructions
+        .iter()
+        .map(|i| (i.span.line, i.span.col, i.span.len))
+        .collect();
+    assert_eq!(spans[2..5], [(2, 1, 9), (2, 11, 9), (2, 21, 9)]);
+
+    let binary = compile(optimize(IR::parse(&program).unwrap()).0).unwrap();
+    assert_eq!(create_and_run_bin(&binary).stdout, [2]);
+}
+
+#[test]
+fn round_trip() {
+    let tables = [
+        TokenTable::brainfuck(),
+        TokenTable::ook(),
+        TokenTable::blub(),
+        custom(),
+    ];
+
+    for source in SOURCES {
+        let program = Program::from(source);
+        let ir = IR::parse(&program).unwrap();
+
+        for table in &tables {
+            let text = table.render_program(&program).unwrap();
+            let back = table.tokenize("<table>", &text);
+            assert_eq!(back.commands(), program.commands(), "{text}");
+
+            // the optimized program is written out differently, but has to
+            // do the same thing
+            let text = table.render_ir(&optimize(ir.clone()).0).unwrap();
+            let back = IR::parse(&table.tokenize("<table>", &text)).unwrap();
+            assert_eq!(output(&back), output(&ir), "{text}");
+        }
+    }
+}
+
+#[test]
+fn custom_tables() {
+    let table = custom();
+    let program = table.tokenize(
+        "custom",
+        "more + +\nwhile right more more left less end   while right say",
+    );
+    let ir = optimize(IR::parse(&program).unwrap()).0;
+    assert_eq!(output(&ir), [6]);
+
+    // rendering uses the first token for a command
+    assert_eq!(
+        table.render(&[Command::Incr, Command::JmpB]).unwrap(),
+        "more end while\n"
+    );
+
+    // the longest token wins
+    let table = TokenTable::new([(Command::Incr, "a"), (Command::Decr, "ab")])
+        .unwrap()
+        .with_separator("");
+    let program = table.tokenize("prefix", "aab a");
+    assert_eq!(
+        program.commands(),
+        [Command::Incr, Command::Decr, Command::Incr]
+    );
+    assert_eq!(table.render_program(&program).unwrap(), "aaba\n");
+}
+
+#[test]
+fn errors() {
+    assert_eq!(
+        TokenTable::new([(Command::Incr, " \n")]),
+        Err(TableError::EmptyToken(Command::Incr))
+    );
+    assert_eq!(
+        TokenTable::new([(Command::Incr, "a  b"), (Command::Decr, "a b")]),
+        Err(TableError::DuplicateToken("a b".to_owned()))
+    );
+
+    let ook = TokenTable::ook();
+    assert_eq!(
+        ook.render(&[Command::Incr, Command::Dump]),
+        Err(RenderError::MissingToken(Command::Dump))
+    );
+
+    let ir: IR = "add 3\nmul_add 2 @1\nwrite".parse().unwrap();
+    assert_eq!(ook.render_ir(&ir), Err(RenderError::UnclearedMulAdd(1)));
+}